@@ -0,0 +1,15 @@
+//! Hosts games over the playtak protocol. The address to listen on may be given as the only
+//! argument and defaults to port 10000, the port of the public server.
+
+use rust_tak::server::Server;
+
+use std::env;
+
+fn main() {
+    let addr = env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("0.0.0.0:10000"));
+    let server = Server::bind(&addr).expect("Failed to bind the server address");
+    println!("Listening on {}", server.local_addr().unwrap());
+    server.run().expect("Failed to accept connections");
+}
@@ -0,0 +1,22 @@
+//! A Tak engine speaking the Tak Engine Interface over stdin and stdout
+
+use rust_tak::engine::tei::TeiEngine;
+
+use std::io::{self, BufRead, Write};
+
+fn main() {
+    let mut engine = TeiEngine::new();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    for line in stdin.lock().lines() {
+        let line = line.expect("Failed to read from stdin");
+        if line.trim() == "quit" {
+            break;
+        }
+        let mut out = stdout.lock();
+        for response in engine.handle(&line) {
+            writeln!(out, "{}", response).expect("Failed to write to stdout");
+        }
+        out.flush().expect("Failed to write to stdout");
+    }
+}
@@ -0,0 +1,65 @@
+//! Replays the games of a playtak archive and reports those with illegal moves, results differing
+//! from the recorded ones or moves which cannot be decoded. Exits with status 1 if any are found.
+//! Games are replayed with their recorded reserves and komi, except those with a komi of half a
+//! flat, which are skipped.
+//!
+//! Usage: verify [ARCHIVE] [--threads N] [--size N] [--player NAME] [--min-rating N]
+//!               [--dates FIRST LAST] [--ids FIRST LAST]
+//!
+//! The archive defaults to games_anon.db and dates are in milliseconds since the Unix epoch.
+
+use rust_tak::game::database::playtak::{verify, Archive, Filter};
+
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut path = String::from("games_anon.db");
+    let mut filter = Filter::new();
+    let mut threads = thread::available_parallelism().map_or(1, |n| n.get());
+    while let Some(arg) = args.next() {
+        let mut value = || -> String { args.next().unwrap_or_else(|| usage(&arg)) };
+        match arg.as_str() {
+            "--threads" => threads = number(&arg, value()),
+            "--size" => filter = filter.with_size(number(&arg, value())),
+            "--player" => filter = filter.with_player(&value()),
+            "--min-rating" => filter = filter.with_min_rating(number(&arg, value())),
+            "--dates" => {
+                let (first, last) = (value(), value());
+                filter = filter.with_dates(number(&arg, first), number(&arg, last));
+            }
+            "--ids" => {
+                let (first, last) = (value(), value());
+                filter = filter.with_ids(number(&arg, first), number(&arg, last));
+            }
+            _ if arg.starts_with("--") => usage(&arg),
+            _ => path = arg,
+        }
+    }
+    let report = Archive::open(&path)
+        .and_then(|archive| verify(&archive, &filter, threads))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2)
+        });
+    println!("{}", report);
+    if !report.is_clean() {
+        process::exit(1);
+    }
+}
+
+fn number<T: FromStr>(option: &str, value: String) -> T {
+    value.parse().unwrap_or_else(|_| usage(option))
+}
+
+fn usage(option: &str) -> ! {
+    eprintln!("Invalid use of {}", option);
+    eprintln!(
+        "Usage: verify [ARCHIVE] [--threads N] [--size N] [--player NAME] [--min-rating N] \
+         [--dates FIRST LAST] [--ids FIRST LAST]"
+    );
+    process::exit(2)
+}
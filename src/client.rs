@@ -0,0 +1,365 @@
+use crate::game::database::{decode_playtak_move, encode_playtak_move, parse_result};
+use crate::game::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+///A message from a playtak server
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Successful login under the given name
+    Welcome(String),
+    /// A game involving this client has started, in which it plays the given color
+    GameStart {
+        id: u32,
+        size: u8,
+        white: String,
+        black: String,
+        color: Color,
+        time: u32,
+    },
+    /// This client is now observing a game
+    Observe {
+        id: u32,
+        size: u8,
+        white: String,
+        black: String,
+        time: u32,
+    },
+    /// A move made in a game being played or observed
+    Move {
+        id: u32,
+        m: Move,
+    },
+    /// The remaining time of each player in seconds
+    Time {
+        id: u32,
+        white: u32,
+        black: u32,
+    },
+    Over {
+        id: u32,
+        result: Victory,
+    },
+    /// The opponent offers a draw, which can be accepted by offering one in return
+    DrawOffer {
+        id: u32,
+    },
+    SeekNew(SeekInfo),
+    SeekRemove(SeekInfo),
+    /// The last command was accepted
+    Ok,
+    /// The last command was rejected
+    Nok,
+    /// Any message the client does not interpret
+    Other(String),
+}
+
+///An open seek as announced by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeekInfo {
+    pub id: u32,
+    pub name: String,
+    pub size: u8,
+    pub time: u32,
+    pub increment: u32,
+    /// The color the seeker wants to play, or None if either will do
+    pub color: Option<Color>,
+}
+
+///A command sent to a playtak server
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Logs in with a password, or as a guest if the name is Guest and there is no password
+    Login {
+        name: String,
+        password: Option<String>,
+    },
+    Seek {
+        size: u8,
+        time: u32,
+        increment: u32,
+        color: Option<Color>,
+    },
+    Accept(u32),
+    Observe(u32),
+    Unobserve(u32),
+    Move(u32, Move),
+    Resign(u32),
+    /// Offers a draw, or accepts one offered by the opponent
+    OfferDraw(u32),
+    Quit,
+}
+
+impl Command {
+    ///The line which sends the command, or None for a move beyond the largest board, which
+    /// playtak notation cannot name
+    pub fn to_line(&self) -> Option<String> {
+        let line = match self {
+            Command::Login { name, password } => match password {
+                Some(password) => format!("Login {} {}", name, password),
+                None => format!("Login {}", name),
+            },
+            Command::Seek {
+                size,
+                time,
+                increment,
+                color,
+            } => {
+                let color = match color {
+                    Some(Color::White) => " W",
+                    Some(Color::Black) => " B",
+                    None => "",
+                };
+                format!("Seek {} {} {}{}", size, time, increment, color)
+            }
+            Command::Accept(id) => format!("Accept {}", id),
+            Command::Observe(id) => format!("Observe {}", id),
+            Command::Unobserve(id) => format!("Unobserve {}", id),
+            Command::Move(id, m) => format!("Game#{} {}", id, encode_playtak_move(m)?),
+            Command::Resign(id) => format!("Game#{} Resign", id),
+            Command::OfferDraw(id) => format!("Game#{} OfferDraw", id),
+            Command::Quit => String::from("quit"),
+        };
+        Some(line)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// A message which looks like a known event but cannot be parsed
+    Malformed(String),
+    /// A message about a game which has not been started or observed
+    UnknownGame(u32),
+    /// A move which is illegal in the local copy of the game
+    IllegalMove {
+        id: u32,
+        m: Move,
+        reason: String,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Malformed(line) => write!(f, "Malformed message {}", line),
+            ClientError::UnknownGame(id) => write!(f, "Unknown game {}", id),
+            ClientError::IllegalMove { id, m, reason } => {
+                write!(f, "Illegal move {} in game {}: {}", m, id, reason)
+            }
+            ClientError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+///Parses a single line sent by a playtak server, without regard to any game state
+pub fn parse_event(line: &str) -> Result<Event, ClientError> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let malformed = || ClientError::Malformed(line.to_string());
+    let number = |index: usize| -> Result<u32, ClientError> {
+        tokens
+            .get(index)
+            .and_then(|t| t.trim_start_matches("Game#").parse().ok())
+            .ok_or_else(malformed)
+    };
+    let size = |index: usize| -> Result<u8, ClientError> {
+        tokens
+            .get(index)
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(malformed)
+    };
+    let event = match tokens.as_slice() {
+        ["OK"] => Event::Ok,
+        ["NOK"] => Event::Nok,
+        ["Welcome", name] if name.ends_with('!') => {
+            Event::Welcome(name.trim_end_matches('!').to_string())
+        }
+        ["Game", "Start", _, _, white, "vs", black, color, ..] => Event::GameStart {
+            id: number(2)?,
+            size: size(3)?,
+            white: white.to_string(),
+            black: black.to_string(),
+            color: match *color {
+                "white" => Color::White,
+                "black" => Color::Black,
+                _ => return Err(malformed()),
+            },
+            time: number(8)?,
+        },
+        ["Observe", _, _, white, "vs", black, ..] => Event::Observe {
+            id: number(1)?,
+            size: size(2)?,
+            white: white.to_string(),
+            black: black.to_string(),
+            time: number(6)?,
+        },
+        ["Seek", action @ "new", ..] | ["Seek", action @ "remove", ..] => {
+            let info = SeekInfo {
+                id: number(2)?,
+                name: tokens.get(3).ok_or_else(malformed)?.to_string(),
+                size: size(4)?,
+                time: number(5)?,
+                increment: number(6)?,
+                color: match tokens.get(7) {
+                    Some(&"W") => Some(Color::White),
+                    Some(&"B") => Some(Color::Black),
+                    _ => None,
+                },
+            };
+            if *action == "new" {
+                Event::SeekNew(info)
+            } else {
+                Event::SeekRemove(info)
+            }
+        }
+        [game, "Time", _, _] if game.starts_with("Game#") => Event::Time {
+            id: number(0)?,
+            white: number(2)?,
+            black: number(3)?,
+        },
+        [game, "Over", result] if game.starts_with("Game#") => Event::Over {
+            id: number(0)?,
+            result: parse_result(result).ok_or_else(malformed)?,
+        },
+        [game, "OfferDraw"] if game.starts_with("Game#") => Event::DrawOffer { id: number(0)? },
+        [game, "P", ..] | [game, "M", ..] if game.starts_with("Game#") => Event::Move {
+            id: number(0)?,
+            m: decode_playtak_move(&tokens[1..].join(" ")).ok_or_else(malformed)?,
+        },
+        _ => Event::Other(line.to_string()),
+    };
+    Ok(event)
+}
+
+///Follows the messages of a playtak server, keeping a local copy of every game started or
+/// observed so that bots and observers can inspect the position.
+#[derive(Default)]
+pub struct Client {
+    games: HashMap<u32, Game>,
+    /// Moves sent by this client which the server has yet to answer with OK or NOK, oldest first
+    pending: VecDeque<(u32, Move)>,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    pub fn game(&self, id: u32) -> Option<&Game> {
+        self.games.get(&id)
+    }
+
+    ///Parses a line from the server, applying it to the local games. Moves the local copy finds
+    /// illegal are reported as errors and leave the game unchanged.
+    pub fn handle(&mut self, line: &str) -> Result<Event, ClientError> {
+        let event = parse_event(line)?;
+        match &event {
+            Event::GameStart { id, size, .. } | Event::Observe { id, size, .. } => {
+                let config = GameConfig::try_new(*size)
+                    .map_err(|_| ClientError::Malformed(line.to_string()))?;
+                self.games.insert(*id, make_standard_game(config));
+            }
+            Event::Move { id, m } => self.apply(*id, m.clone())?,
+            Event::Ok => {
+                if let Some((id, m)) = self.pending.pop_front() {
+                    self.apply(id, m)?;
+                }
+            }
+            Event::Nok => {
+                self.pending.pop_front();
+            }
+            // A move which was still unanswered when the game ended was not made
+            Event::Over { id, .. } => self.pending.retain(|(game, _)| game != id),
+            _ => {}
+        }
+        Ok(event)
+    }
+
+    ///Checks a move of this client against the local copy of a game, returning the command which
+    /// sends it to the server. The move is made in the local copy once the server answers OK, and
+    /// dropped if it answers NOK.
+    pub fn play(&mut self, id: u32, m: Move) -> Result<Command, ClientError> {
+        let game = self.games.get(&id).ok_or(ClientError::UnknownGame(id))?;
+        let checked = match game.result() {
+            Victory::Neither => game.rules.check_move(&m).map_err(|e| e.to_string()),
+            result => Err(GameOver { result }.to_string()),
+        };
+        if let Err(reason) = checked {
+            return Err(ClientError::IllegalMove { id, m, reason });
+        }
+        self.pending.push_back((id, m.clone()));
+        Ok(Command::Move(id, m))
+    }
+
+    fn apply(&mut self, id: u32, m: Move) -> Result<(), ClientError> {
+        let game = self
+            .games
+            .get_mut(&id)
+            .ok_or(ClientError::UnknownGame(id))?;
+        match game.do_ply(m.clone()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ClientError::IllegalMove {
+                id,
+                m,
+                reason: e.to_string(),
+            }),
+        }
+    }
+}
+
+///A connection to a playtak server which keeps a Client in sync with the messages received
+pub struct Connection {
+    client: Client,
+    reader: Lines<BufReader<TcpStream>>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Connection> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Connection {
+            client: Client::new(),
+            reader: BufReader::new(stream.try_clone()?).lines(),
+            writer: stream,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    ///Sends a command, failing with InvalidInput for a move playtak notation cannot name
+    pub fn send(&mut self, command: &Command) -> io::Result<()> {
+        let line = command.to_line().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Move has no playtak notation")
+        })?;
+        writeln!(self.writer, "{}", line)
+    }
+
+    ///Sends a move to the server, making it in the local copy of the game once the server accepts it
+    pub fn play(&mut self, id: u32, m: Move) -> Result<(), ClientError> {
+        let command = self.client.play(id, m)?;
+        self.send(&command)?;
+        Ok(())
+    }
+
+    ///Waits for the next message from the server, returning None once the connection closes
+    pub fn next_event(&mut self) -> Result<Option<Event>, ClientError> {
+        match self.reader.next() {
+            Some(line) => Ok(Some(self.client.handle(&line?)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+pub mod alphabeta;
+pub mod mcts;
+pub mod tei;
+
+use crate::game::*;
+
+use std::time::Duration;
+
+/// Score of a won position, less the plies needed to reach it so that faster wins are preferred
+pub const WIN_SCORE: i32 = 1_000_000;
+
+///Bounds on how long a search may run. A search with neither bound runs to MAX_DEPTH.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SearchLimits {
+    pub depth: Option<u32>,
+    pub time: Option<Duration>,
+}
+
+impl SearchLimits {
+    pub fn depth(depth: u32) -> SearchLimits {
+        SearchLimits {
+            depth: Some(depth),
+            time: None,
+        }
+    }
+
+    pub fn time(time: Duration) -> SearchLimits {
+        SearchLimits {
+            depth: None,
+            time: Some(time),
+        }
+    }
+}
+
+/// Deepest iteration attempted when no depth limit is given
+pub const MAX_DEPTH: u32 = 64;
+
+///The outcome of a search. The score is from the perspective of the player to move.
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub best_move: Option<Move>,
+    pub score: i32,
+    /// The deepest fully completed iteration
+    pub depth: u32,
+    /// The expected line of play beginning with the best move
+    pub pv: Vec<Move>,
+    pub nodes: u64,
+}
+
+///The winner of a finished game, or None for a draw or an unfinished game
+pub fn winner(result: &Victory) -> Option<Color> {
+    match result {
+        Victory::WhiteRoad | Victory::WhiteFlat(_) | Victory::WhiteOther => Some(Color::White),
+        Victory::BlackRoad | Victory::BlackFlat(_) | Victory::BlackOther => Some(Color::Black),
+        Victory::Neither | Victory::Draw => None,
+    }
+}
+
+///A static evaluation of the position from white's perspective. Top flats are worth the most as
+/// they decide flat games, stacks are worth more to the player controlling them, and pieces
+/// lined up along a row or column are rewarded as the beginnings of roads.
+pub fn evaluate(state: &State) -> i32 {
+    let size = state.size as usize;
+    let mut score = 0;
+    let mut rows = vec![[0i32; 2]; size];
+    let mut cols = vec![[0i32; 2]; size];
+    for ((row, col), tile) in state.board.indexed_iter() {
+        let top = match tile.top() {
+            Some(top) => top,
+            None => continue,
+        };
+        let sign = match top.color {
+            Color::White => 1,
+            Color::Black => -1,
+        };
+        let mut value = match top.kind {
+            PieceKind::Flat => 100,
+            PieceKind::Wall => 40,
+            PieceKind::Cap => 80,
+        };
+        for piece in tile.stack[..tile.stack.len() - 1].iter() {
+            value += if piece.color == top.color { 30 } else { 10 };
+        }
+        score += sign * value;
+        if top.kind != PieceKind::Wall {
+            let index = if sign > 0 { 0 } else { 1 };
+            rows[row][index] += 1;
+            cols[col][index] += 1;
+        }
+    }
+    for line in rows.iter().chain(cols.iter()) {
+        score += 5 * (line[0] * line[0] - line[1] * line[1]);
+    }
+    score
+}
+
+///A small xorshift generator so that engines can make random choices reproducibly
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck at zero
+        Rng {
+            state: seed ^ 0x2545_F491_4F6C_DD1D,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    ///A uniformly chosen index below the bound, which must be positive
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
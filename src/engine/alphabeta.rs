@@ -0,0 +1,142 @@
+use crate::engine::*;
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Nodes searched between checks of the clock
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+///An iterative deepening negamax search with alpha-beta pruning. The best move found for each
+/// position is remembered by Zobrist hash and searched first on the next iteration.
+pub struct AlphaBeta {
+    limits: SearchLimits,
+    start: Instant,
+    nodes: u64,
+    stopped: bool,
+    best_moves: HashMap<u64, Move>,
+}
+
+impl AlphaBeta {
+    pub fn new(limits: SearchLimits) -> AlphaBeta {
+        AlphaBeta {
+            limits,
+            start: Instant::now(),
+            nodes: 0,
+            stopped: false,
+            best_moves: HashMap::new(),
+        }
+    }
+
+    ///Searches the current position, leaving it unchanged. The result of the deepest completed
+    /// iteration is returned; if the time runs out during the first, the best move is the best
+    /// one found so far, or else the first legal move.
+    pub fn search(&mut self, rules: &mut dyn Rules) -> SearchResult {
+        self.start = Instant::now();
+        self.nodes = 0;
+        self.stopped = false;
+        let mut result = SearchResult {
+            best_move: rules.legal_moves().into_iter().next(),
+            score: 0,
+            depth: 0,
+            pv: Vec::new(),
+            nodes: 0,
+        };
+        let max_depth = self.limits.depth.unwrap_or(MAX_DEPTH);
+        for depth in 1..=max_depth {
+            let mut pv = Vec::new();
+            let score = self.negamax(rules, depth, -WIN_SCORE - 1, WIN_SCORE + 1, 0, &mut pv);
+            if self.stopped {
+                if result.depth == 0 && !pv.is_empty() {
+                    result.best_move = Some(pv[0].clone());
+                }
+                break;
+            }
+            result.best_move = pv.first().cloned();
+            result.score = score;
+            result.depth = depth;
+            result.pv = pv;
+            // A forced result will not change with more depth
+            if score.abs() > WIN_SCORE - MAX_DEPTH as i32 || result.best_move.is_none() {
+                break;
+            }
+        }
+        result.nodes = self.nodes;
+        result
+    }
+
+    fn out_of_time(&self) -> bool {
+        match self.limits.time {
+            Some(time) => self.start.elapsed() >= time,
+            None => false,
+        }
+    }
+
+    fn negamax(
+        &mut self,
+        rules: &mut dyn Rules,
+        depth: u32,
+        mut alpha: i32,
+        beta: i32,
+        ply: u32,
+        pv: &mut Vec<Move>,
+    ) -> i32 {
+        self.nodes += 1;
+        if self.nodes.is_multiple_of(TIME_CHECK_INTERVAL) && self.out_of_time() {
+            self.stopped = true;
+        }
+        if self.stopped {
+            return 0;
+        }
+        let side = rules.side_to_move();
+        let result = rules.check_win();
+        if result != Victory::Neither {
+            return match winner(&result) {
+                Some(color) if color == side => WIN_SCORE - ply as i32,
+                Some(_) => -(WIN_SCORE - ply as i32),
+                None => 0,
+            };
+        }
+        if depth == 0 {
+            let score = evaluate(rules.get_state());
+            return if side == Color::White { score } else { -score };
+        }
+        let hash = rules.get_state().hash;
+        let mut moves = rules.legal_moves();
+        if let Some(best) = self.best_moves.get(&hash) {
+            if let Some(index) = moves.iter().position(|m| m == best) {
+                moves.swap(0, index);
+            }
+        }
+        let mut best_score = -WIN_SCORE - 1;
+        for m in moves {
+            rules
+                .make_move(m.clone())
+                .expect("Generated moves are legal");
+            let mut line = Vec::new();
+            let score = -self.negamax(rules, depth - 1, -beta, -alpha, ply + 1, &mut line);
+            rules.unmake_move();
+            if self.stopped {
+                return 0;
+            }
+            if score > best_score {
+                best_score = score;
+                if score > alpha {
+                    alpha = score;
+                    pv.clear();
+                    pv.push(m.clone());
+                    pv.append(&mut line);
+                    self.best_moves.insert(hash, m);
+                }
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        best_score
+    }
+}
+
+///Searches the current position with the given limits
+pub fn search(rules: &mut dyn Rules, limits: SearchLimits) -> SearchResult {
+    AlphaBeta::new(limits).search(rules)
+}
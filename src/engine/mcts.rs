@@ -0,0 +1,241 @@
+use crate::engine::*;
+
+use std::time::Instant;
+
+///Chooses moves during the random playouts of a Monte Carlo tree search
+pub trait PlayoutPolicy {
+    /// Picks one of the legal moves, which is never empty, in the current position
+    fn choose(&mut self, rules: &dyn Rules, moves: Vec<Move>) -> Move;
+}
+
+///Plays uniformly random legal moves
+pub struct RandomPlayout {
+    rng: Rng,
+}
+
+impl RandomPlayout {
+    pub fn new(seed: u64) -> RandomPlayout {
+        RandomPlayout {
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl PlayoutPolicy for RandomPlayout {
+    fn choose(&mut self, _rules: &dyn Rules, mut moves: Vec<Move>) -> Move {
+        let index = self.rng.below(moves.len());
+        moves.swap_remove(index)
+    }
+}
+
+///Budget and tuning of a Monte Carlo tree search. The search stops at whichever of the iteration
+/// and time budgets runs out first; at least one of them must be given.
+#[derive(Clone, Copy, Debug)]
+pub struct MctsConfig {
+    pub iterations: Option<u64>,
+    pub time: Option<Duration>,
+    /// The UCT exploration constant
+    pub exploration: f64,
+    /// Playouts still running after this many plies are decided by a flat count
+    pub playout_limit: u32,
+    pub seed: u64,
+}
+
+impl Default for MctsConfig {
+    fn default() -> MctsConfig {
+        MctsConfig {
+            iterations: Some(10_000),
+            time: None,
+            exploration: std::f64::consts::SQRT_2,
+            playout_limit: 200,
+            seed: 0,
+        }
+    }
+}
+
+///Search statistics of a move from the root position
+#[derive(Clone, Debug)]
+pub struct MoveStats {
+    pub mv: Move,
+    pub visits: u32,
+    /// Mean playout reward for the player making the move, 1 for a win and 0.5 for a draw
+    pub value: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct MctsResult {
+    /// The most visited move
+    pub best_move: Option<Move>,
+    pub iterations: u64,
+    /// Statistics of the moves from the root which the search expanded, most visited first. Legal
+    /// moves never tried, as when the search stops before expanding them all, are left out.
+    pub stats: Vec<MoveStats>,
+}
+
+struct Node {
+    mv: Option<Move>,
+    /// The player who made the move leading to this node
+    mover: Color,
+    children: Vec<usize>,
+    untried: Vec<Move>,
+    visits: u32,
+    reward: f64,
+}
+
+///A Monte Carlo tree search using the UCT selection rule
+pub struct Mcts {
+    config: MctsConfig,
+    policy: Box<dyn PlayoutPolicy>,
+    rng: Rng,
+    nodes: Vec<Node>,
+}
+
+impl Mcts {
+    ///Creates a search using uniformly random playouts
+    pub fn new(config: MctsConfig) -> Mcts {
+        let policy = Box::new(RandomPlayout::new(config.seed));
+        Mcts::with_policy(config, policy)
+    }
+
+    pub fn with_policy(config: MctsConfig, policy: Box<dyn PlayoutPolicy>) -> Mcts {
+        Mcts {
+            config,
+            policy,
+            rng: Rng::new(config.seed.wrapping_add(1)),
+            nodes: Vec::new(),
+        }
+    }
+
+    ///Searches the current position, leaving it unchanged
+    pub fn search(&mut self, rules: &mut dyn Rules) -> MctsResult {
+        let start = Instant::now();
+        let root_moves = if rules.check_win() == Victory::Neither {
+            rules.legal_moves()
+        } else {
+            Vec::new()
+        };
+        self.nodes = vec![Node {
+            mv: None,
+            mover: rules.side_to_move().opposite(),
+            children: Vec::new(),
+            untried: root_moves,
+            visits: 0,
+            reward: 0.0,
+        }];
+        let mut iterations = 0;
+        loop {
+            if self
+                .config
+                .iterations
+                .is_some_and(|limit| iterations >= limit)
+                || self.config.time.is_some_and(|time| start.elapsed() >= time)
+                || (self.config.iterations.is_none() && self.config.time.is_none())
+            {
+                break;
+            }
+            self.iterate(rules);
+            iterations += 1;
+        }
+        let mut stats: Vec<_> = self.nodes[0]
+            .children
+            .iter()
+            .map(|&child| {
+                let node = &self.nodes[child];
+                MoveStats {
+                    mv: node.mv.clone().unwrap(),
+                    visits: node.visits,
+                    value: node.reward / f64::from(node.visits),
+                }
+            })
+            .collect();
+        stats.sort_by_key(|s| std::cmp::Reverse(s.visits));
+        MctsResult {
+            best_move: stats.first().map(|s| s.mv.clone()),
+            iterations,
+            stats,
+        }
+    }
+
+    ///Runs one round of selection, expansion, playout and backpropagation
+    fn iterate(&mut self, rules: &mut dyn Rules) {
+        let mut path = vec![0];
+        let mut node = 0;
+        // Selection
+        while self.nodes[node].untried.is_empty() && !self.nodes[node].children.is_empty() {
+            node = self.select_child(node);
+            let mv = self.nodes[node].mv.clone().unwrap();
+            rules.make_move(mv).expect("Tree moves are legal");
+            path.push(node);
+        }
+        // Expansion
+        if !self.nodes[node].untried.is_empty() {
+            let index = self.rng.below(self.nodes[node].untried.len());
+            let mv = self.nodes[node].untried.swap_remove(index);
+            let mover = rules.side_to_move();
+            rules.make_move(mv.clone()).expect("Tree moves are legal");
+            let untried = if rules.check_win() == Victory::Neither {
+                rules.legal_moves()
+            } else {
+                Vec::new()
+            };
+            self.nodes.push(Node {
+                mv: Some(mv),
+                mover,
+                children: Vec::new(),
+                untried,
+                visits: 0,
+                reward: 0.0,
+            });
+            let child = self.nodes.len() - 1;
+            self.nodes[node].children.push(child);
+            path.push(child);
+        }
+        // Playout
+        let mut plies = 0;
+        let mut result = rules.check_win();
+        while result == Victory::Neither {
+            if plies >= self.config.playout_limit {
+                result = rules.flat_game();
+                break;
+            }
+            let moves = rules.legal_moves();
+            if moves.is_empty() {
+                break;
+            }
+            let mv = self.policy.choose(rules, moves);
+            rules
+                .make_move(mv)
+                .expect("Playout policies choose legal moves");
+            plies += 1;
+            result = rules.check_win();
+        }
+        for _ in 0..plies + path.len() as u32 - 1 {
+            rules.unmake_move();
+        }
+        // Backpropagation
+        let winner = winner(&result);
+        for index in path {
+            let node = &mut self.nodes[index];
+            node.visits += 1;
+            node.reward += match winner {
+                Some(color) if color == node.mover => 1.0,
+                Some(_) => 0.0,
+                None => 0.5,
+            };
+        }
+    }
+
+    fn select_child(&self, node: usize) -> usize {
+        let log_visits = f64::from(self.nodes[node].visits).ln();
+        let uct = |child: usize| {
+            let child = &self.nodes[child];
+            let visits = f64::from(child.visits);
+            child.reward / visits + self.config.exploration * (log_visits / visits).sqrt()
+        };
+        *self.nodes[node]
+            .children
+            .iter()
+            .max_by(|&&a, &&b| uct(a).partial_cmp(&uct(b)).unwrap())
+            .unwrap()
+    }
+}
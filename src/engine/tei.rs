@@ -0,0 +1,132 @@
+use crate::engine::alphabeta::AlphaBeta;
+use crate::engine::*;
+
+///Time used when a go command gives no limits
+const DEFAULT_MOVE_TIME: Duration = Duration::from_secs(1);
+
+///The state of an engine speaking the Tak Engine Interface, a text protocol modelled on UCI. Each
+/// line received from the GUI is passed to handle, which returns the lines to send back.
+pub struct TeiEngine {
+    game: Game,
+    size: u8,
+}
+
+impl Default for TeiEngine {
+    fn default() -> TeiEngine {
+        TeiEngine::new()
+    }
+}
+
+impl TeiEngine {
+    pub fn new() -> TeiEngine {
+        TeiEngine {
+            game: make_standard_game(GameConfig::new(5)),
+            size: 5,
+        }
+    }
+
+    pub fn get_game(&self) -> &Game {
+        &self.game
+    }
+
+    ///Handles one command. Unknown commands are ignored, as the protocol requires, and malformed
+    /// ones are reported with an info string.
+    pub fn handle(&mut self, line: &str) -> Vec<String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let result = match tokens.first() {
+            Some(&"tei") => Ok(vec![
+                format!("id name rust-tak {}", env!("CARGO_PKG_VERSION")),
+                format!("id author {}", env!("CARGO_PKG_AUTHORS")),
+                String::from("teiok"),
+            ]),
+            Some(&"isready") => Ok(vec![String::from("readyok")]),
+            Some(&"teinewgame") => self.new_game(&tokens[1..]),
+            Some(&"position") => self.position(&tokens[1..]),
+            Some(&"go") => self.go(&tokens[1..]),
+            _ => Ok(Vec::new()),
+        };
+        result.unwrap_or_else(|message| vec![format!("info string {}", message)])
+    }
+
+    fn new_game(&mut self, args: &[&str]) -> Result<Vec<String>, String> {
+        let size = match args.first() {
+            Some(size) => size
+                .parse()
+                .ok()
+                .filter(|&size| GameConfig::try_new(size).is_ok())
+                .ok_or_else(|| format!("unsupported size {}", size))?,
+            None => self.size,
+        };
+        self.size = size;
+        self.game = make_standard_game(GameConfig::new(size));
+        Ok(Vec::new())
+    }
+
+    ///Sets up `startpos` or `tps <board> <player> <move>`, then plays the moves following `moves`
+    fn position(&mut self, args: &[&str]) -> Result<Vec<String>, String> {
+        let moves_index = args.iter().position(|&t| t == "moves");
+        let setup = &args[..moves_index.unwrap_or(args.len())];
+        let mut game = match setup.first() {
+            Some(&"startpos") => make_standard_game(GameConfig::new(self.size)),
+            Some(&"tps") => {
+                let state = State::from_tps(&setup[1..].join(" ")).map_err(|e| e.to_string())?;
+                Game::new(Box::new(StandardRules::new(state)))
+            }
+            _ => return Err(String::from("expected startpos or tps")),
+        };
+        if let Some(index) = moves_index {
+            for ptn in args[index + 1..].iter() {
+                let m = game
+                    .parse_move(ptn)
+                    .map_err(|e| format!("invalid move {}: {}", ptn, e))?;
+                game.do_ply(m)
+                    .map_err(|e| format!("illegal move {}: {}", ptn, e))?;
+            }
+        }
+        self.game = game;
+        Ok(Vec::new())
+    }
+
+    ///Searches with the limits given as `movetime`, `depth` or `wtime`, `btime`, `winc` and `binc`
+    fn go(&mut self, args: &[&str]) -> Result<Vec<String>, String> {
+        let mut params = std::collections::HashMap::new();
+        for pair in args.chunks(2) {
+            if let [name, value] = pair {
+                let value: u64 = value
+                    .parse()
+                    .map_err(|_| format!("invalid value {} for {}", value, name))?;
+                params.insert(*name, value);
+            }
+        }
+        let (time, increment) = match self.game.side_to_move() {
+            Color::White => ("wtime", "winc"),
+            Color::Black => ("btime", "binc"),
+        };
+        let mut limits = SearchLimits {
+            depth: params.get("depth").map(|&d| d as u32),
+            time: params.get("movetime").map(|&t| Duration::from_millis(t)),
+        };
+        if let Some(&remaining) = params.get(time) {
+            let inc = params.get(increment).cloned().unwrap_or(0);
+            limits.time = Some(Duration::from_millis(remaining / 20 + inc / 2));
+        }
+        if limits.depth.is_none() && limits.time.is_none() {
+            limits.time = Some(DEFAULT_MOVE_TIME);
+        }
+        let result = AlphaBeta::new(limits).search(&mut *self.game.rules);
+        let best = result
+            .best_move
+            .ok_or_else(|| String::from("no legal moves"))?;
+        let pv: Vec<String> = result.pv.iter().map(|m| m.to_string()).collect();
+        Ok(vec![
+            format!(
+                "info depth {} score cp {} nodes {} pv {}",
+                result.depth,
+                result.score,
+                result.nodes,
+                pv.join(" ")
+            ),
+            format!("bestmove {}", best),
+        ])
+    }
+}
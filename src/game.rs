@@ -1,14 +1,21 @@
 use regex::Regex;
 
 
+pub mod clock;
 pub mod database;
+pub mod perft;
 pub mod rules;
 pub mod state;
+pub mod zobrist;
 pub use self::rules::*;
 pub use self::state::*;
 
+use self::clock::Clock;
+
 use super::Error;
+use failure::bail;
 use ndarray::Array2;
+use std::fmt;
 pub trait TakGame {
     /// Attemps to perform all actions necessary to progress forward one ply
     fn do_ply(&mut self, m: Move) -> Result<Victory, Error> {
@@ -56,25 +63,178 @@ pub trait TakGame {
     fn get_mut_state(&self) -> &mut State;
 }
 
+///The error returned when a move or action is attempted in a game which has already ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameOver {
+    pub result: Victory,
+}
+
+impl fmt::Display for GameOver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The game is already over with result {}", self.result)
+    }
+}
+
+impl std::error::Error for GameOver {}
+
 pub struct Game {
     pub rules: Box<Rules>,
     pub ply: u32,
+    pub clock: Option<Clock>,
+    /// The result of a finished game, whether decided on the board or by an action
+    outcome: Option<Victory>,
+    /// The player with a standing draw offer
+    draw_offer: Option<Color>,
 }
 
 impl Game {
     ///Creates a new game, consuming a given rule set and opening
     pub fn new(rules: Box<Rules>) -> Game {
-        Game { rules, ply: 0 }
+        let outcome = match rules.check_win() {
+            Victory::Neither => None,
+            result => Some(result),
+        };
+        Game {
+            rules,
+            ply: 0,
+            clock: None,
+            outcome,
+            draw_offer: None,
+        }
     }
-    ///Attempts to execute a given move. Returns a tuple containing first whether or not the move
-    /// Attemps to perform all actions necessary to progress forward one ply
+    ///Attempts to execute a given move, returning the result of the game after it. Once a result
+    /// other than Neither is returned the game is over and further moves fail with GameOver.
     pub fn do_ply(&mut self, m: Move) -> Result<Victory, Error> {
+        self.ensure_in_progress()?;
+        let mover = self.side_to_move();
         self.rules.make_move(m)?;
-        Ok(self.rules.check_win())
+        if let Some(clock) = &mut self.clock {
+            // A move made after the flag fell does not count
+            if let Err(color) = clock.press() {
+                self.rules.unmake_move();
+                return Ok(self.end(flag_fall(color)));
+            }
+        }
+        // Moving instead of answering a draw offer declines it
+        if self.draw_offer == Some(mover.opposite()) {
+            self.draw_offer = None;
+        }
+        match self.rules.check_win() {
+            Victory::Neither => Ok(Victory::Neither),
+            result => Ok(self.end(result)),
+        }
+    }
+    ///The result of the game, or Neither while it is in progress
+    pub fn result(&self) -> Victory {
+        self.outcome.unwrap_or(Victory::Neither)
+    }
+    ///Whether the game has ended, either on the board or by an action
+    pub fn is_over(&self) -> bool {
+        self.outcome.is_some()
+    }
+    fn end(&mut self, result: Victory) -> Victory {
+        self.outcome = Some(result);
+        self.draw_offer = None;
+        result
+    }
+    fn ensure_in_progress(&mut self) -> Result<(), Error> {
+        self.check_time();
+        match self.outcome {
+            Some(result) => Err(GameOver { result }.into()),
+            None => Ok(()),
+        }
+    }
+    ///Ends the game as a win for the opponent of the given player
+    pub fn resign(&mut self, color: Color) -> Result<Victory, Error> {
+        self.ensure_in_progress()?;
+        Ok(self.end(forfeit(color)))
+    }
+    ///Ends the game as a win for the opponent of a player who left it. Leaving is scored as a
+    /// resignation.
+    pub fn abandon(&mut self, color: Color) -> Result<Victory, Error> {
+        self.resign(color)
+    }
+    ///Offers a draw to the opponent of the given player, ending the game as a draw if the opponent
+    /// had already offered one. The offer stands until it is declined or the opponent moves.
+    pub fn offer_draw(&mut self, color: Color) -> Result<Victory, Error> {
+        self.ensure_in_progress()?;
+        if self.draw_offer == Some(color.opposite()) {
+            return Ok(self.end(Victory::Draw));
+        }
+        self.draw_offer = Some(color);
+        Ok(Victory::Neither)
+    }
+    ///Accepts the draw offered by the opponent of the given player
+    pub fn accept_draw(&mut self, color: Color) -> Result<Victory, Error> {
+        self.ensure_in_progress()?;
+        if self.draw_offer != Some(color.opposite()) {
+            bail!("No draw has been offered");
+        }
+        Ok(self.end(Victory::Draw))
+    }
+    ///Declines the draw offered by the opponent of the given player
+    pub fn decline_draw(&mut self, color: Color) -> Result<(), Error> {
+        self.ensure_in_progress()?;
+        if self.draw_offer != Some(color.opposite()) {
+            bail!("No draw has been offered");
+        }
+        self.draw_offer = None;
+        Ok(())
+    }
+    ///Returns the player with a standing draw offer, if any
+    pub fn draw_offer(&self) -> Option<Color> {
+        self.draw_offer
+    }
+    ///Calls off a game in which at most one ply has been played. Aborted games have no winner and
+    /// are recorded as draws.
+    pub fn abort(&mut self) -> Result<Victory, Error> {
+        self.ensure_in_progress()?;
+        if self.get_state().history.len() >= 2 {
+            bail!("Games can only be aborted before the second move");
+        }
+        Ok(self.end(Victory::Draw))
+    }
+    ///Reverts the last ply made, returning its move, or None if the game has no moves. A result
+    /// decided on the board by the undone ply is cleared so that the game continues from the
+    /// earlier position, while a game ended by resignation, abandonment, abort, agreement or flag
+    /// fall stays over. The clock, if any, is left alone.
+    pub fn undo_ply(&mut self) -> Option<Move> {
+        let decided_on_board =
+            self.outcome.is_some() && self.outcome == Some(self.rules.check_win());
+        let m = self.rules.unmake_move()?;
+        if decided_on_board {
+            self.outcome = None;
+        }
+        Some(m)
+    }
+    ///Puts the game on a clock, starting the time of the player to move
+    pub fn set_clock(&mut self, mut clock: Clock) {
+        clock.start(self.side_to_move());
+        self.clock = Some(clock);
+    }
+    ///Ends a game in progress if the player to move has run out of time, returning the victory of
+    /// their opponent, or Neither otherwise. Moves and actions check the clock this way first, so
+    /// they fail with GameOver once the flag has fallen.
+    pub fn check_time(&mut self) -> Victory {
+        if self.outcome.is_some() {
+            return Victory::Neither;
+        }
+        match self.clock.as_ref().and_then(|c| c.flagged()) {
+            Some(color) => self.end(flag_fall(color)),
+            None => Victory::Neither,
+        }
+    }
+    ///Returns the color of the player whose turn it is, see Rules::side_to_move
+    pub fn side_to_move(&self) -> Color {
+        self.rules.side_to_move()
     }
     pub fn legal_move(&self, m: Move) -> bool {
         self.rules.legal_move(m)
     }
+    ///Returns every legal move for the player whose turn it is
+    pub fn legal_moves(&self) -> Vec<Move> {
+        self.rules.legal_moves()
+    }
     ///Prints the board with the most relevant board state information
     pub fn print_board(&self) {
         println!("{}", self.rules.get_state());
@@ -85,6 +245,35 @@ impl Game {
         self.rules.get_state()
     }
 
+    ///Reads a ptn move in the current position. Other than with ptn_move, stack moves may carry
+    /// no more pieces than the carry limit of the rules, and must carry the crush marker exactly
+    /// when a capstone would flatten a wall.
+    pub fn parse_move(&self, string: &str) -> Result<Move, MoveParseError> {
+        let (m, crush) = parse_ptn_move(string)?;
+        if let Move::Throw(count, source, dir, vec) = &m {
+            if *count > self.rules.carry_limit() {
+                return Err(MoveParseError::CarryLimitExceeded(*count));
+            }
+            if crush != self.crushes(*source, *dir, vec.len() as u8) {
+                return Err(MoveParseError::CrushMismatch);
+            }
+        }
+        Ok(m)
+    }
+
+    ///Whether a capstone on top of the source would land on a wall after the given distance
+    fn crushes(&self, source: Square, dir: Direction, distance: u8) -> bool {
+        let state = self.get_state();
+        let on_board = |square: &Square| !state.out_of_bounds(square.row, square.col);
+        let top = |square: Square| state.get_tile(square.row, square.col).top().map(|p| p.kind);
+        match source.shift(dir, distance).filter(on_board) {
+            Some(target) if on_board(&source) => {
+                top(source) == Some(PieceKind::Cap) && top(target) == Some(PieceKind::Wall)
+            }
+            _ => false,
+        }
+    }
+
     pub fn get_mut_state(&mut self) -> &mut State {
         self.rules.get_mut_state()
     }
@@ -103,46 +292,88 @@ impl Game {
     }
 }
 
-///Transforms a ptn string into a Move that can be understood by the server, or None if the given
-/// string was deemed an invalid ptn string.
-pub fn ptn_move(string: &str) -> Option<Move> {
+///The result of a game in which the given player ran out of time
+fn flag_fall(color: Color) -> Victory {
+    forfeit(color)
+}
+
+///The result of a game lost by the given player other than on the board
+fn forfeit(color: Color) -> Victory {
+    match color {
+        Color::White => Victory::BlackOther,
+        Color::Black => Victory::WhiteOther,
+    }
+}
+
+///The reason a string could not be read as a ptn move
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveParseError {
+    /// The string does not follow the ptn move grammar
+    Syntax,
+    /// A stack move which picks up no pieces
+    EmptyPickup,
+    /// A stack move which picks up more pieces than the rules allow to be carried
+    CarryLimitExceeded(u8),
+    /// A stack move which drops no pieces on one of the squares it passes
+    ZeroDrop,
+    /// A stack move which drops a different number of pieces than it picks up
+    CountMismatch { picked: u8, dropped: u32 },
+    /// A crush marker on a move which flattens no wall, or a missing one on a move which does
+    CrushMismatch,
+}
+
+impl fmt::Display for MoveParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MoveParseError::Syntax => write!(f, "Not a ptn move"),
+            MoveParseError::EmptyPickup => write!(f, "A stack move must pick up pieces"),
+            MoveParseError::CarryLimitExceeded(count) => {
+                write!(f, "Cannot carry {} pieces", count)
+            }
+            MoveParseError::ZeroDrop => write!(f, "Every square passed must be dropped on"),
+            MoveParseError::CountMismatch { picked, dropped } => {
+                write!(f, "Picked up {} pieces but dropped {}", picked, dropped)
+            }
+            MoveParseError::CrushMismatch => {
+                write!(f, "Crush marker does not match the flattening of a wall")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MoveParseError {}
+
+///Transforms a ptn string into a Move that can be understood by the server. Stack moves are
+/// checked to carry at most 8 pieces, the size of the largest board, and to drop exactly what they
+/// pick up, but since the board is unknown the crush marker is only checked to follow a single
+/// piece. Game::parse_move performs the checks which depend on the position and the rules.
+pub fn ptn_move(string: &str) -> Result<Move, MoveParseError> {
+    match parse_ptn_move(string)? {
+        (Move::Throw(count, ..), _) if count > 8 => Err(MoveParseError::CarryLimitExceeded(count)),
+        (m, _) => Ok(m),
+    }
+}
+
+///Parses a ptn move, also returning whether it carries the crush marker
+fn parse_ptn_move(string: &str) -> Result<(Move, bool), MoveParseError> {
     lazy_static! {
         static ref RE: Regex =
-            Regex::new(r"^(\d)?(?i)([CS])?([a-h])([1-8])(([<>+-])([1-8]+)?(\*)?)?$").unwrap();
+            Regex::new(r"^(\d)?(?i)([CS])?([a-h])([1-8])(([<>+-])(\d+)?(\*)?)?$").unwrap();
     }
-    let res = RE.captures(string);
-    let res = match res {
-        Some(x) => x,
-        _ => return None,
-    };
-    match res.get(6) {
+    let res = RE.captures(string).ok_or(MoveParseError::Syntax)?;
+    let square = Square::new(
+        res[4].parse::<u8>().unwrap() - 1,
+        col_match(res[3].to_lowercase()),
+    );
+    let dir = match res.get(6) {
         //Directional symbol
-        Some(d) => {
-            //stack move
-            let dir = String::from(d.as_str()).pop().unwrap_or('+');
-            let vec = res.get(7).map_or("1", |m| m.as_str()); //1 for elision
-            let vec: Vec<_> = vec
-                .chars()
-                .map(|c| c.to_digit(16).unwrap_or(8) as u8)
-                .collect();
-            return Some(Move::Throw(
-                (
-                    res.get(1)
-                        .map_or(1, |x| x.as_str().parse::<u8>().unwrap_or(1)),
-                    res.get(4)
-                        .map_or(0, |m| m.as_str().parse::<u8>().unwrap_or(1) - 1),
-                    col_match(
-                        res.get(3)
-                            .map_or(String::from(""), |m| m.as_str().to_lowercase()),
-                    ),
-                ),
-                dir,
-                vec,
-                String::from(string),
-            ));
-        }
+        Some(d) => Direction::from_symbol(d.as_str().chars().next().unwrap())
+            .ok_or(MoveParseError::Syntax)?,
         None => {
-            //place
+            //place, which cannot pick up pieces
+            if res.get(1).is_some() {
+                return Err(MoveParseError::Syntax);
+            }
             let kind = match res.get(2).map_or("", |m| m.as_str()) {
                 "S" => PieceKind::Wall,
                 "C" => PieceKind::Cap,
@@ -150,20 +381,41 @@ pub fn ptn_move(string: &str) -> Option<Move> {
                 "c" => PieceKind::Cap,
                 _ => PieceKind::Flat,
             };
-            return Some(Move::Place(
-                kind,
-                (
-                    res.get(4)
-                        .map_or(0, |m| m.as_str().parse::<u8>().unwrap_or(1) - 1),
-                    col_match(
-                        res.get(3)
-                            .map_or(String::from(""), |m| m.as_str().to_lowercase()),
-                    ),
-                ),
-                String::from(string),
-            ));
+            return Ok((Move::Place(kind, square), false));
         }
+    };
+    //stack move, which cannot name a piece kind
+    if res.get(2).is_some() {
+        return Err(MoveParseError::Syntax);
+    }
+    let count = res.get(1).map_or(1, |x| x.as_str().parse::<u8>().unwrap());
+    if count == 0 {
+        return Err(MoveParseError::EmptyPickup);
     }
+    //The whole stack is dropped on the next square if the drops are elided
+    let vec: Vec<u8> = match res.get(7) {
+        Some(m) => m
+            .as_str()
+            .chars()
+            .map(|c| c.to_digit(10).unwrap() as u8)
+            .collect(),
+        None => vec![count],
+    };
+    if vec.contains(&0) {
+        return Err(MoveParseError::ZeroDrop);
+    }
+    let dropped = vec.iter().map(|x| *x as u32).sum();
+    if dropped != count as u32 {
+        return Err(MoveParseError::CountMismatch {
+            picked: count,
+            dropped,
+        });
+    }
+    let crush = res.get(8).is_some();
+    if crush && vec[vec.len() - 1] != 1 {
+        return Err(MoveParseError::CrushMismatch);
+    }
+    Ok((Move::Throw(count, square, dir, vec), crush))
 }
 
 fn col_match(string: String) -> u8 {
@@ -180,8 +432,71 @@ fn col_match(string: String) -> u8 {
         _ => 0,
     }
 }
-///Creates a game with standard rules and a standard opening of the given size
-pub fn make_standard_game(size: u8) -> Game {
-    let r = StandardRules::new(State::new(size));
-    return Game::new(Box::new(r));
-}
\ No newline at end of file
+///The board size, reserves and komi a game is set up with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameConfig {
+    pub size: u8,
+    /// Stones in each player's reserve, not counting capstones
+    pub stones: i32,
+    pub caps: i32,
+    /// Flats added to black's count in a flat game
+    pub komi: u32,
+}
+
+impl GameConfig {
+    ///The standard reserves for a board size, without komi, or an error if the size is not
+    /// between 3 and 8
+    pub fn try_new(size: u8) -> Result<GameConfig, Error> {
+        let (stones, caps) = match standard_reserves(size) {
+            Some(reserves) => reserves,
+            None => bail!("Unsupported board size {}", size),
+        };
+        Ok(GameConfig {
+            size,
+            stones,
+            caps,
+            komi: 0,
+        })
+    }
+
+    ///The standard reserves for a board size, without komi. A convenience for sizes known to be
+    /// supported, which panics if the size is not between 3 and 8; use try_new otherwise.
+    pub fn new(size: u8) -> GameConfig {
+        GameConfig::try_new(size).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn with_komi(self, komi: u32) -> GameConfig {
+        GameConfig { komi, ..self }
+    }
+
+    pub fn with_reserves(self, stones: i32, caps: i32) -> GameConfig {
+        GameConfig {
+            stones,
+            caps,
+            ..self
+        }
+    }
+
+    ///An empty board with both players holding the configured reserves
+    pub fn state(&self) -> State {
+        let player1 = Player::new(Color::White, self.stones, self.caps);
+        let player2 = Player::new(Color::Black, self.stones, self.caps);
+        State::new_with_players(self.size, player1, player2)
+    }
+}
+
+///Creates a game with standard rules and a standard opening from a configuration, using komi rules
+/// if the configuration has komi
+pub fn make_standard_game(config: GameConfig) -> Game {
+    standard_game_from(config.state(), config.komi)
+}
+
+///Creates a game with standard rules played on from the given position, using komi rules if there
+/// is komi
+pub(crate) fn standard_game_from(state: State, komi: u32) -> Game {
+    if komi == 0 {
+        Game::new(Box::new(StandardRules::new(state)))
+    } else {
+        Game::new(Box::new(KomiRules { state, komi }))
+    }
+}
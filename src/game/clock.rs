@@ -0,0 +1,170 @@
+use super::Color;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///A source of monotonic time, measured from an arbitrary starting point. Clocks read time through
+/// this trait so that tests can control it.
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+///Reads the monotonic clock of the system
+pub struct RealTime {
+    start: Instant,
+}
+
+impl Default for RealTime {
+    fn default() -> RealTime {
+        RealTime::new()
+    }
+}
+
+impl RealTime {
+    pub fn new() -> RealTime {
+        RealTime {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl TimeSource for RealTime {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+///A time source which stands still until it is advanced
+#[derive(Default)]
+pub struct ManualTime {
+    now: Mutex<Duration>,
+}
+
+impl ManualTime {
+    pub fn new() -> ManualTime {
+        ManualTime::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl TimeSource for ManualTime {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+///The time each player starts with, the Fischer increment added after each of their moves, and the
+/// delay at the start of each turn before their time begins to run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeControl {
+    pub initial: Duration,
+    pub increment: Duration,
+    pub delay: Duration,
+}
+
+impl TimeControl {
+    pub fn new(initial: Duration, increment: Duration) -> TimeControl {
+        TimeControl {
+            initial,
+            increment,
+            delay: Duration::from_secs(0),
+        }
+    }
+
+    pub fn with_delay(self, delay: Duration) -> TimeControl {
+        TimeControl { delay, ..self }
+    }
+}
+
+///A chess clock for two players. Only the clock of the player to move runs, and pressing it ends
+/// their turn.
+pub struct Clock {
+    control: TimeControl,
+    source: Arc<dyn TimeSource>,
+    white: Duration,
+    black: Duration,
+    to_move: Color,
+    turn_start: Duration,
+    flagged: Option<Color>,
+}
+
+impl Clock {
+    ///Creates a clock with white's time running
+    pub fn new(control: TimeControl, source: Arc<dyn TimeSource>) -> Clock {
+        let turn_start = source.now();
+        Clock {
+            control,
+            source,
+            white: control.initial,
+            black: control.initial,
+            to_move: Color::White,
+            turn_start,
+            flagged: None,
+        }
+    }
+
+    pub fn control(&self) -> TimeControl {
+        self.control
+    }
+
+    ///Starts the turn of the given player without charging anyone for the time passed
+    pub fn start(&mut self, color: Color) {
+        self.to_move = color;
+        self.turn_start = self.source.now();
+    }
+
+    pub fn to_move(&self) -> Color {
+        self.to_move
+    }
+
+    ///The time the player to move has used this turn, after the delay
+    fn used(&self) -> Duration {
+        (self.source.now() - self.turn_start).saturating_sub(self.control.delay)
+    }
+
+    ///The time a player has left, including the running time of the player to move
+    pub fn remaining(&self, color: Color) -> Duration {
+        match color {
+            Color::White if self.to_move == Color::White => self.white.saturating_sub(self.used()),
+            Color::Black if self.to_move == Color::Black => self.black.saturating_sub(self.used()),
+            Color::White => self.white,
+            Color::Black => self.black,
+        }
+    }
+
+    ///The player whose time has run out, if any
+    pub fn flagged(&self) -> Option<Color> {
+        self.flagged.or_else(|| {
+            if self.remaining(self.to_move) == Duration::from_secs(0) {
+                Some(self.to_move)
+            } else {
+                None
+            }
+        })
+    }
+
+    ///Ends the turn of the player to move, charging them for it and adding the increment. If their
+    /// time had already run out the clock stops and the flagged player is returned as an error.
+    pub fn press(&mut self) -> Result<(), Color> {
+        if let Some(color) = self.flagged {
+            return Err(color);
+        }
+        let now = self.source.now();
+        let used = (now - self.turn_start).saturating_sub(self.control.delay);
+        let time = match self.to_move {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        };
+        if used >= *time {
+            self.flagged = Some(self.to_move);
+            return Err(self.to_move);
+        }
+        *time = *time - used + self.control.increment;
+        self.to_move = self.to_move.opposite();
+        self.turn_start = now;
+        Ok(())
+    }
+}
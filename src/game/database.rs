@@ -1,35 +1,327 @@
+use regex::Regex;
 use std::error::Error;
 
 use super::Game;
 use super::Move;
+use super::{Color, GameConfig, PieceKind, Player, Rules, Square, StandardRules, State, Victory};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
-pub fn read_formatted_ptn(string: String) -> Option<(Game, Vec<Move>)> {
-    let mut game: Option<Game> = None;
-    let mut vec = Vec::new();
-    for s in string.lines() {
-        if s.starts_with("[") {
-            //Game information lines
-            if s.starts_with("[Size ") {
-                let v: Vec<&str> = s.split("\"").collect();
-                let num = v[1].parse().unwrap();
-                game = Some(super::make_standard_game(num));
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[cfg(feature = "sqlite")]
+pub mod playtak;
+
+///A game read from a PTN document
+#[derive(Debug)]
+pub struct PtnGame {
+    /// Tag pairs in the order they appear in the document
+    pub tags: Vec<(String, String)>,
+    pub moves: Vec<Move>,
+    /// The result token ending the move list, e.g. R-0, if there was one
+    pub result: Option<String>,
+}
+
+impl PtnGame {
+    ///Returns the value of the first tag with the given name
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    ///Creates a game set up at the starting position of the document, which is the TPS tag if
+    /// present or an empty board of the size given by the Size tag, with the reserves and komi of
+    /// the Flats, Caps and Komi tags. Returns None if the document specifies neither or a tag
+    /// value cannot be used.
+    pub fn new_game(&self) -> Option<Game> {
+        let komi = self.tag("Komi").map_or(Some(0), |k| k.parse().ok())?;
+        if let Some(tps) = self.tag("TPS") {
+            let state = State::from_tps(tps).ok()?;
+            return Some(super::standard_game_from(state, komi));
+        }
+        let size = self.tag("Size")?.parse().ok()?;
+        let config = GameConfig::try_new(size).ok()?.with_komi(komi);
+        let stones = self
+            .tag("Flats")
+            .map_or(Some(config.stones), |s| s.parse().ok())?;
+        let caps = self
+            .tag("Caps")
+            .map_or(Some(config.caps), |c| c.parse().ok())?;
+        Some(super::make_standard_game(
+            config.with_reserves(stones, caps),
+        ))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PtnErrorKind {
+    /// A tag pair not of the form [Name "Value"]
+    MalformedTag,
+    /// A Size or TPS tag whose value cannot describe a board
+    InvalidTagValue(String),
+    UnterminatedComment,
+    InvalidMove(String),
+    /// A move or result following the result of the game
+    MoveAfterResult(String),
+}
+
+///An error reading a PTN document, located by the 1-indexed line and column where it occurred
+#[derive(Debug, PartialEq)]
+pub struct PtnError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: PtnErrorKind,
+}
+
+impl fmt::Display for PtnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match &self.kind {
+            PtnErrorKind::MalformedTag => String::from("Malformed tag pair"),
+            PtnErrorKind::InvalidTagValue(tag) => format!("Invalid value for tag {}", tag),
+            PtnErrorKind::UnterminatedComment => String::from("Unterminated comment"),
+            PtnErrorKind::InvalidMove(m) => format!("Invalid move {}", m),
+            PtnErrorKind::MoveAfterResult(m) => format!("{} follows the game result", m),
+        };
+        write!(
+            f,
+            "{} at line {}, column {}",
+            description, self.line, self.column
+        )
+    }
+}
+
+impl Error for PtnError {}
+
+const RESULTS: [&str; 8] = ["R-0", "0-R", "F-0", "0-F", "1-0", "0-1", "1/2-1/2", "0-0"];
+
+///Reads a PTN document. Tag pairs, comments in braces, move numbers, the -- placeholder for a
+/// skipped first move, annotation suffixes such as ' and ?! and a trailing result are understood.
+/// If the tags describe a starting position, the moves are played out on it, so moves which are
+/// illegal or carry a wrong crush marker are rejected as invalid.
+pub fn read_formatted_ptn(string: &str) -> Result<PtnGame, PtnError> {
+    let mut game = PtnGame {
+        tags: Vec::new(),
+        moves: Vec::new(),
+        result: None,
+    };
+    let mut chars = PtnCursor {
+        chars: string.chars().peekable(),
+        line: 1,
+        column: 1,
+    };
+    //The position the moves are played in, set up from the tags once the first move is read
+    let mut board: Option<Option<Game>> = None;
+    while let Some(&ch) = chars.peek() {
+        let (line, column) = (chars.line, chars.column);
+        let error = |kind| PtnError { line, column, kind };
+        if ch.is_whitespace() {
+            chars.next();
+        } else if ch == '[' {
+            chars.next();
+            let (name, value) =
+                read_tag(&mut chars).ok_or_else(|| error(PtnErrorKind::MalformedTag))?;
+            let valid = match name.as_str() {
+                "Size" => value.parse().is_ok_and(|s| GameConfig::try_new(s).is_ok()),
+                "TPS" => State::from_tps(&value).is_ok(),
+                _ => true,
+            };
+            if !valid {
+                return Err(error(PtnErrorKind::InvalidTagValue(name)));
+            }
+            game.tags.push((name, value));
+        } else if ch == '{' {
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(_) => {}
+                    None => return Err(error(PtnErrorKind::UnterminatedComment)),
+                }
+            }
+        } else {
+            let mut token = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() || ch == '{' || ch == '[' {
+                    break;
+                }
+                token.push(ch);
+                chars.next();
+            }
+            let is_move_number = token.ends_with('.')
+                && token[..token.len() - 1].chars().all(|c| c.is_ascii_digit());
+            if is_move_number || token == "--" {
+                continue;
+            }
+            if game.result.is_some() {
+                return Err(error(PtnErrorKind::MoveAfterResult(token)));
+            }
+            if RESULTS.contains(&token.as_str()) {
+                game.result = Some(token);
+                continue;
+            }
+            let stripped = token.trim_end_matches(|c| "'\"!?".contains(c));
+            let position = board.get_or_insert_with(|| game.new_game());
+            let m = match position {
+                Some(position) => position
+                    .parse_move(stripped)
+                    .ok()
+                    .filter(|m| position.do_ply(m.clone()).is_ok()),
+                None => super::ptn_move(stripped).ok(),
+            };
+            match m {
+                Some(m) => game.moves.push(m),
+                None => return Err(error(PtnErrorKind::InvalidMove(token))),
             }
-            continue;
-        } else if s.len() < 1 {
-            //Ignore blank lines
-            continue;
         }
-        let split_line: Vec<&str> = s.split_whitespace().collect();
-        vec.push(super::ptn_move(split_line[1]).unwrap());
-        if split_line.len() > 2 {
-            vec.push(super::ptn_move(split_line[2]).unwrap())
+    }
+    Ok(game)
+}
+
+///Characters of a PTN document along with the position of the next character
+struct PtnCursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> PtnCursor<'a> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
         }
+        Some(ch)
     }
-    match game {
-        Some(g) => return Some((g, vec)),
-        _ => return None,
+}
+
+///Reads the remainder of a tag pair after its opening bracket
+fn read_tag(chars: &mut PtnCursor) -> Option<(String, String)> {
+    let mut name = String::new();
+    while chars.peek()?.is_alphanumeric() {
+        name.push(chars.next()?);
+    }
+    while chars.peek()?.is_whitespace() {
+        chars.next();
+    }
+    if name.is_empty() || chars.next()? != '"' {
+        return None;
+    }
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => value.push(chars.next()?),
+            '\n' => return None,
+            ch => value.push(ch),
+        }
+    }
+    while chars.peek()?.is_whitespace() {
+        chars.next();
+    }
+    if chars.next()? != ']' {
+        return None;
+    }
+    Some((name, value))
+}
+
+///Writes a game as a complete PTN document. Tags that cannot be derived from the game itself, such
+/// as Player1, Player2 and Date, are supplied by the caller and written first in the given order,
+/// followed by the Size tag, a TPS tag if the game did not start from an empty board, the Result,
+/// Komi, Flats and Caps tags, the numbered moves and the result.
+pub fn write_ptn(game: &Game, tags: &[(&str, &str)]) -> String {
+    let state = game.get_state();
+    let result = game.result();
+    let (flats, caps) = starting_reserves(game, Color::White);
+    let mut out = String::new();
+    for (name, value) in tags.iter() {
+        if !["Size", "TPS", "Result", "Komi", "Flats", "Caps"].contains(name) {
+            out.push_str(&format!("[{} \"{}\"]\n", name, escape_tag_value(value)));
+        }
+    }
+    out.push_str(&format!("[Size \"{}\"]\n", state.size));
+    if let Some(tps) = starting_tps(state) {
+        out.push_str(&format!("[TPS \"{}\"]\n", tps));
+    }
+    if result != Victory::Neither {
+        out.push_str(&format!("[Result \"{}\"]\n", result));
     }
+    out.push_str(&format!("[Komi \"{}\"]\n", game.rules.komi()));
+    out.push_str(&format!("[Flats \"{}\"]\n", flats));
+    out.push_str(&format!("[Caps \"{}\"]\n", caps));
+    out.push('\n');
+
+    //One line of tokens per move number, with the result after the last move
+    let mut lines: Vec<Vec<String>> = Vec::new();
+    for (ply, m) in (state.start_ply..).zip(state.history.iter()) {
+        let number = format!("{}.", ply / 2 + 1);
+        match (ply % 2, lines.last_mut()) {
+            (1, Some(line)) => line.push(m.to_string()),
+            (1, None) => lines.push(vec![number, String::from("--"), m.to_string()]),
+            _ => lines.push(vec![number, m.to_string()]),
+        }
+    }
+    if result != Victory::Neither {
+        match lines.last_mut() {
+            Some(line) => line.push(result.to_string()),
+            None => lines.push(vec![result.to_string()]),
+        }
+    }
+    let lines: Vec<String> = lines.iter().map(|line| line.join(" ")).collect();
+    out.push_str(&lines.join("\n"));
+    out.push('\n');
+    out
+}
+
+///Escapes the quotes and backslashes of a tag value the way read_tag expects
+fn escape_tag_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+///The TPS of the position a game started from, found by taking back every move on a copy of it,
+/// or None if it started from an empty board at the first move
+fn starting_tps(state: &State) -> Option<String> {
+    let player = |p: &Player| Player::new(p.color, p.pieces, p.caps);
+    let mut start =
+        State::new_with_players(state.size, player(&state.player1), player(&state.player2));
+    for (tile, original) in start.board.iter_mut().zip(state.board.iter()) {
+        tile.stack = original.stack.clone();
+    }
+    start.history = state.history.clone();
+    start.crushes = state.crushes.clone();
+    start.start_ply = state.start_ply;
+    start.hash = start.compute_hash();
+    let mut rules = StandardRules::new(start);
+    while rules.unmake_move().is_some() {}
+    let start = rules.get_state();
+    if start.start_ply == 0 && start.board.iter().all(|tile| tile.is_empty()) {
+        return None;
+    }
+    Some(start.to_tps())
+}
+
+///The stones and capstones a player started the game with, counting both their reserve and their
+/// pieces on the board
+fn starting_reserves(game: &Game, color: Color) -> (i32, i32) {
+    let player = game.get_state().get_player(color);
+    let (mut flats, mut caps) = (player.pieces, player.caps);
+    for piece in game.get_board().iter().flat_map(|t| t.stack.iter()) {
+        if piece.color == color {
+            match piece.kind {
+                PieceKind::Cap => caps += 1,
+                _ => flats += 1,
+            }
+        }
+    }
+    (flats, caps)
 }
 
 pub fn read_ptn_file(name_string: &str) -> Result<String, Box<Error>> {
@@ -39,80 +331,183 @@ pub fn read_ptn_file(name_string: &str) -> Result<String, Box<Error>> {
     return Ok(out_string);
 }
 
-pub fn decode_playtak_notation(str: &str) -> Vec<Move> {
-    let moves = str.split(",");
-    let mut vec = Vec::new();
-    for m in moves {
-        let transformed = transform_notation(m);
-        vec.push(transformed);
+///Encodes a move in the notation used by the playtak server, e.g. P A1 C or M A1 A3 1 2, or None
+/// if the move places on or throws past a square beyond the largest board, which cannot be named
+pub fn encode_playtak_move(m: &Move) -> Option<String> {
+    let name = |square: Square| {
+        if square.row < 8 && square.col < 8 {
+            Some(square.to_string().to_uppercase())
+        } else {
+            None
+        }
+    };
+    match m {
+        Move::Place(kind, square) => {
+            let square = name(*square)?;
+            Some(match kind {
+                PieceKind::Flat => format!("P {}", square),
+                PieceKind::Wall => format!("P {} W", square),
+                PieceKind::Cap => format!("P {} C", square),
+            })
+        }
+        Move::Throw(_, source, dir, vec) => {
+            let dest = source.shift(*dir, vec.len() as u8).and_then(name)?;
+            let mut out = format!("M {} {}", name(*source)?, dest);
+            for val in vec.iter() {
+                out.push_str(&format!(" {}", val));
+            }
+            Some(out)
+        }
     }
-    let vec: Vec<_> = vec
-        .into_iter()
-        .map(|m| super::ptn_move(&m).unwrap())
-        .collect();
-    vec
 }
 
-fn transform_notation(str: &str) -> String {
+///Encodes a sequence of moves as a playtak database notation string, the inverse of
+/// decode_playtak_notation, or None if one of the moves cannot be encoded
+pub fn encode_playtak_notation(moves: &[Move]) -> Option<String> {
+    let moves = moves
+        .iter()
+        .map(encode_playtak_move)
+        .collect::<Option<Vec<_>>>()?;
+    Some(moves.join(","))
+}
+
+///Encodes every move made so far in a game as a playtak database notation string
+pub fn encode_playtak_game(game: &Game) -> Option<String> {
+    encode_playtak_notation(&game.get_state().history)
+}
+
+///A move of playtak database notation which cannot be decoded
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    /// The position of the move in the comma separated list, starting from 0
+    pub index: usize,
+    pub token: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cannot decode move {} \"{}\"", self.index, self.token)
+    }
+}
+
+impl Error for DecodeError {}
+
+///Decodes a playtak database notation string, a comma separated list of moves such as
+/// P A1,P E5,M A1 A3 1 2. An empty string is a game without moves.
+pub fn decode_playtak_notation(str: &str) -> Result<Vec<Move>, DecodeError> {
+    if str.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    str.split(',')
+        .enumerate()
+        .map(|(index, token)| {
+            decode_playtak_move(token).ok_or_else(|| DecodeError {
+                index,
+                token: token.to_string(),
+            })
+        })
+        .collect()
+}
+
+///Decodes a single move sent to or by the playtak server, e.g. P A1 C or M A1 A3 1 2, or None if
+/// it is malformed.
+pub fn decode_playtak_move(string: &str) -> Option<Move> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"^(P [A-H][1-8]( [CW])?|M [A-H][1-8] [A-H][1-8]( [1-8])+)$").unwrap();
+    }
+    let string = string.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !RE.is_match(&string) {
+        return None;
+    }
+    let m = super::ptn_move(&transform_notation(&string)?).ok()?;
+    // Moves which are not in a straight line or do not end on the given square are rejected
+    if encode_playtak_move(&m).as_ref() == Some(&string) {
+        Some(m)
+    } else {
+        None
+    }
+}
+
+///Rewrites a playtak move as ptn, or None if it is malformed
+fn transform_notation(str: &str) -> Option<String> {
     let split_move: Vec<_> = str.split_whitespace().collect();
-    match split_move[0] {
-        "P" => {
-            if split_move.len() <= 2 {
-                return String::from(split_move[1].to_lowercase());
-            } else {
-                let mut s = {
-                    if split_move[2] == "C" {
-                        String::from("C")
-                    } else {
-                        String::from("S")
-                    }
-                };
-                s.push_str(&split_move[1].to_lowercase());
-                return s;
-            }
+    match split_move.as_slice() {
+        ["P", square] => Some(square.to_lowercase()),
+        ["P", square, kind] => {
+            let prefix = match *kind {
+                "C" => "C",
+                "W" => "S",
+                _ => return None,
+            };
+            Some(format!("{}{}", prefix, square.to_lowercase()))
         }
-        "M" => {
-            let source: Vec<_> = split_move[1].chars().collect();
-            let dest: Vec<_> = split_move[2].chars().collect();
-            let direction = {
-                let col_dif = decode_column(source[0]) - decode_column(dest[0]);
-                if col_dif < 0 {
-                    ">"
-                } else if col_dif > 0 {
-                    "<"
-                } else {
-                    let row_dif = source[1].to_digit(10).unwrap() as i32
-                        - dest[1].to_digit(10).unwrap() as i32;
-                    if row_dif > 0 {
-                        "-"
-                    } else {
-                        "+"
-                    }
-                }
+        ["M", source, dest, drops @ ..] => {
+            let (source_col, source_row) = decode_square(source)?;
+            let (dest_col, dest_row) = decode_square(dest)?;
+            let direction = if source_col < dest_col {
+                ">"
+            } else if source_col > dest_col {
+                "<"
+            } else if source_row > dest_row {
+                "-"
+            } else {
+                "+"
             };
-            let mut res_string = String::from(split_move[1]);
-            res_string.push_str(direction);
             let mut picked_up = 0;
-            for i in 3..split_move.len() {
-                res_string.push_str(split_move[i]);
-                picked_up += split_move[i].parse::<u32>().unwrap();
+            for drop in drops.iter() {
+                picked_up += drop.parse::<u8>().ok()? as u32;
             }
-            let mut result = picked_up.to_string();
-            result.push_str(&res_string);
-            return result;
+            Some(format!(
+                "{}{}{}{}",
+                picked_up,
+                source,
+                direction,
+                drops.concat()
+            ))
         }
-        _ => return String::from(""),
+        _ => None,
+    }
+}
+
+///Parses a result such as R-0 or 1/2-1/2. Flat wins are given a count of zero since playtak
+/// does not record it.
+pub fn parse_result(string: &str) -> Option<Victory> {
+    let result = match string {
+        "R-0" => Victory::WhiteRoad,
+        "0-R" => Victory::BlackRoad,
+        "F-0" => Victory::WhiteFlat(0),
+        "0-F" => Victory::BlackFlat(0),
+        "1-0" => Victory::WhiteOther,
+        "0-1" => Victory::BlackOther,
+        "1/2-1/2" => Victory::Draw,
+        "0-0" => Victory::Neither,
+        _ => return None,
+    };
+    Some(result)
+}
+
+///Splits a square such as C4 into its column and row
+fn decode_square(square: &str) -> Option<(u8, u32)> {
+    let mut chars = square.chars();
+    let col = decode_column(chars.next()?)?;
+    let row = chars.next()?.to_digit(10)?;
+    if chars.next().is_some() {
+        return None;
     }
+    Some((col, row))
 }
 
-fn decode_column(ch: char) -> i32 {
+fn decode_column(ch: char) -> Option<u8> {
     match ch {
-        'A' => 1,
-        'B' => 2,
-        'C' => 3,
-        'D' => 4,
-        'E' => 5,
-        'F' => 6,
-        _ => 0,
-    }
-}
\ No newline at end of file
+        'A' => Some(1),
+        'B' => Some(2),
+        'C' => Some(3),
+        'D' => Some(4),
+        'E' => Some(5),
+        'F' => Some(6),
+        'G' => Some(7),
+        'H' => Some(8),
+        _ => None,
+    }
+}
@@ -0,0 +1,489 @@
+//! Reads the archive of games played on the playtak server, distributed as the sqlite database
+//! games_anon.db. Only available with the `sqlite` feature.
+
+use super::{decode_playtak_notation, parse_result, DecodeError};
+use crate::game::{make_standard_game, GameConfig, Move, Victory};
+
+use sqlite::{Connection, Cursor, Value};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+const COLUMNS: &str = "id, date, size, player_white, player_black, notation, result, timertime, \
+                       timerinc, rating_white, rating_black, komi, pieces, capstones";
+
+///An archived game: its details, its moves and the result recorded by the server. Flat wins are
+/// given a count of zero since the archive does not record it.
+pub type ArchivedGame = (GameInfo, Vec<Move>, Victory);
+
+///The details of an archived game other than its moves
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameInfo {
+    pub id: i64,
+    /// When the game was played, in milliseconds since the Unix epoch
+    pub date: i64,
+    pub size: u8,
+    pub white: String,
+    pub black: String,
+    /// The result as recorded by the server, e.g. R-0
+    pub result: String,
+    /// The time each player starts with, in seconds
+    pub time: i64,
+    /// The time added after each move, in seconds
+    pub increment: i64,
+    pub rating_white: i64,
+    pub rating_black: i64,
+    /// Flats added to black's count in a flat game, in half flats as the server records it
+    pub komi: i64,
+    /// The stones each player starts with, or a negative number for the standard reserve
+    pub pieces: i64,
+    /// The capstones each player starts with, or a negative number for the standard reserve
+    pub capstones: i64,
+}
+
+impl GameInfo {
+    ///The setup of the game, or None if it cannot be played by our rules, which only support
+    /// komi of whole flats
+    pub fn config(&self) -> Option<GameConfig> {
+        if self.komi < 0 || self.komi % 2 != 0 {
+            return None;
+        }
+        let config = GameConfig::try_new(self.size)
+            .ok()?
+            .with_komi((self.komi / 2) as u32);
+        let stones = if self.pieces < 0 {
+            config.stones
+        } else {
+            self.pieces as i32
+        };
+        let caps = if self.capstones < 0 {
+            config.caps
+        } else {
+            self.capstones as i32
+        };
+        Some(config.with_reserves(stones, caps))
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    /// There is no archive at the given path
+    NotFound(PathBuf),
+    Sqlite(sqlite::Error),
+    /// A column of a game holds a value which cannot be read
+    Malformed {
+        id: i64,
+        column: &'static str,
+    },
+    /// The moves of a game cannot be decoded
+    Decode {
+        id: i64,
+        error: DecodeError,
+    },
+    /// A thread replaying games panicked, leaving the report incomplete
+    ReplayPanicked,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::NotFound(path) => write!(f, "No archive at {}", path.display()),
+            ArchiveError::Sqlite(e) => write!(f, "{}", e),
+            ArchiveError::Malformed { id, column } => {
+                write!(f, "Game {} has a malformed {}", id, column)
+            }
+            ArchiveError::Decode { id, error } => write!(f, "Game {}: {}", id, error),
+            ArchiveError::ReplayPanicked => write!(f, "A replay thread panicked"),
+        }
+    }
+}
+
+impl Error for ArchiveError {}
+
+impl From<sqlite::Error> for ArchiveError {
+    fn from(e: sqlite::Error) -> ArchiveError {
+        ArchiveError::Sqlite(e)
+    }
+}
+
+///Restricts the games read from an archive to those meeting every condition set
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub size: Option<u8>,
+    /// A player of either color
+    pub player: Option<String>,
+    /// The first and last date included, in milliseconds since the Unix epoch
+    pub dates: Option<(i64, i64)>,
+    /// Games with this result. Flat wins match regardless of their count.
+    pub result: Option<Victory>,
+    /// The rating both players must have at least
+    pub min_rating: Option<i64>,
+    /// The first and last id included
+    pub ids: Option<(i64, i64)>,
+}
+
+impl Filter {
+    ///A filter which admits every game
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    pub fn with_size(self, size: u8) -> Filter {
+        Filter {
+            size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn with_player(self, player: &str) -> Filter {
+        Filter {
+            player: Some(player.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_dates(self, first: i64, last: i64) -> Filter {
+        Filter {
+            dates: Some((first, last)),
+            ..self
+        }
+    }
+
+    pub fn with_result(self, result: Victory) -> Filter {
+        Filter {
+            result: Some(result),
+            ..self
+        }
+    }
+
+    pub fn with_min_rating(self, rating: i64) -> Filter {
+        Filter {
+            min_rating: Some(rating),
+            ..self
+        }
+    }
+
+    pub fn with_ids(self, first: i64, last: i64) -> Filter {
+        Filter {
+            ids: Some((first, last)),
+            ..self
+        }
+    }
+
+    ///The conditions of the filter as an SQL expression along with the values to bind to it
+    fn condition(&self) -> (String, Vec<Value>) {
+        let mut condition = String::from("1");
+        let mut values = Vec::new();
+        if let Some(size) = self.size {
+            condition.push_str(" AND size = ?");
+            values.push(Value::Integer(size as i64));
+        }
+        if let Some(player) = &self.player {
+            condition.push_str(" AND (player_white = ? OR player_black = ?)");
+            values.push(Value::String(player.clone()));
+            values.push(Value::String(player.clone()));
+        }
+        if let Some((first, last)) = self.dates {
+            condition.push_str(" AND date BETWEEN ? AND ?");
+            values.push(Value::Integer(first));
+            values.push(Value::Integer(last));
+        }
+        if let Some(result) = self.result {
+            condition.push_str(" AND result = ?");
+            values.push(Value::String(result.to_string()));
+        }
+        if let Some(rating) = self.min_rating {
+            condition.push_str(" AND rating_white >= ? AND rating_black >= ?");
+            values.push(Value::Integer(rating));
+            values.push(Value::Integer(rating));
+        }
+        if let Some((first, last)) = self.ids {
+            condition.push_str(" AND id BETWEEN ? AND ?");
+            values.push(Value::Integer(first));
+            values.push(Value::Integer(last));
+        }
+        (condition, values)
+    }
+}
+
+///An open playtak archive
+pub struct Archive {
+    connection: Connection,
+}
+
+impl Archive {
+    ///Opens an existing archive, such as games_anon.db
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive, ArchiveError> {
+        let path = path.as_ref();
+        // Opening a missing database would create an empty one
+        if !path.is_file() {
+            return Err(ArchiveError::NotFound(path.to_path_buf()));
+        }
+        Ok(Archive {
+            connection: Connection::open(path)?,
+        })
+    }
+
+    ///Iterates over the games admitted by the filter in the order they were played
+    pub fn games(&self, filter: &Filter) -> Result<Games<'_>, ArchiveError> {
+        let (condition, values) = filter.condition();
+        self.select(&condition, &values)
+    }
+
+    ///Reads the game with the given id, if there is one
+    pub fn game(&self, id: i64) -> Result<Option<ArchivedGame>, ArchiveError> {
+        self.select("id = ?", &[Value::Integer(id)])?
+            .next()
+            .transpose()
+    }
+
+    ///The number of games admitted by the filter
+    pub fn count(&self, filter: &Filter) -> Result<i64, ArchiveError> {
+        let (condition, values) = filter.condition();
+        let query = format!("SELECT COUNT(*) FROM games WHERE {}", condition);
+        let mut cursor = self.connection.prepare(query)?.cursor();
+        cursor.bind(&values)?;
+        match cursor.next()? {
+            Some(row) => Ok(row[0].as_integer().unwrap_or(0)),
+            None => Ok(0),
+        }
+    }
+
+    fn select(&self, condition: &str, values: &[Value]) -> Result<Games<'_>, ArchiveError> {
+        let query = format!(
+            "SELECT {} FROM games WHERE {} ORDER BY id",
+            COLUMNS, condition
+        );
+        let mut cursor = self.connection.prepare(query)?.cursor();
+        cursor.bind(values)?;
+        Ok(Games {
+            cursor,
+            failed: false,
+        })
+    }
+}
+
+///The games read from an archive. Iteration stops after an error from the database, while games
+/// which cannot be read are returned as errors without ending it.
+pub struct Games<'a> {
+    cursor: Cursor<'a>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Games<'a> {
+    type Item = Result<ArchivedGame, ArchiveError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.cursor.next() {
+            Ok(Some(row)) => Some(read_game(row)),
+            Ok(None) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+///Reads a row selecting COLUMNS
+fn read_game(row: &[Value]) -> Result<ArchivedGame, ArchiveError> {
+    // The id is the primary key and always an integer
+    let id = row[0].as_integer().unwrap_or_default();
+    let malformed = |column| ArchiveError::Malformed { id, column };
+    let integer = |index: usize, column| row[index].as_integer().ok_or_else(|| malformed(column));
+    let string = |index: usize, column| {
+        row[index]
+            .as_string()
+            .map(String::from)
+            .ok_or_else(|| malformed(column))
+    };
+    let size = integer(2, "size")?;
+    if !(3..=8).contains(&size) {
+        return Err(malformed("size"));
+    }
+    let info = GameInfo {
+        id,
+        date: integer(1, "date")?,
+        size: size as u8,
+        white: string(3, "player_white")?,
+        black: string(4, "player_black")?,
+        result: string(6, "result")?,
+        time: integer(7, "timertime")?,
+        increment: integer(8, "timerinc")?,
+        rating_white: integer(9, "rating_white")?,
+        rating_black: integer(10, "rating_black")?,
+        komi: integer(11, "komi")?,
+        pieces: integer(12, "pieces")?,
+        capstones: integer(13, "capstones")?,
+    };
+    let victory = parse_result(&info.result).ok_or_else(|| malformed("result"))?;
+    let moves = decode_playtak_notation(&string(5, "notation")?)
+        .map_err(|error| ArchiveError::Decode { id, error })?;
+    Ok((info, moves, victory))
+}
+
+///A way in which an archived game disagrees with our rules
+#[derive(Clone, Debug, PartialEq)]
+pub enum Discrepancy {
+    /// A column of the game holds a value which cannot be read
+    Malformed(&'static str),
+    /// The moves of the game cannot be decoded
+    Undecodable(DecodeError),
+    /// A move of the game is rejected by the rules, possibly because the game already ended
+    IllegalMove { ply: usize, m: Move, reason: String },
+    /// Replaying the game ends it differently from the recorded result
+    ResultMismatch { stored: Victory, replayed: Victory },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Discrepancy::Malformed(column) => write!(f, "Malformed {}", column),
+            Discrepancy::Undecodable(error) => write!(f, "{}", error),
+            Discrepancy::IllegalMove { ply, m, reason } => {
+                write!(f, "Illegal move {} at ply {}: {}", m, ply, reason)
+            }
+            Discrepancy::ResultMismatch { stored, replayed } => {
+                write!(f, "Recorded {} but replayed {}", stored, replayed)
+            }
+        }
+    }
+}
+
+///The outcome of verifying the games of an archive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// The number of games read from the archive
+    pub games: usize,
+    /// Every game which disagrees with our rules, ordered by id
+    pub discrepancies: Vec<(i64, Discrepancy)>,
+    /// The ids of the games which were not replayed because of a komi of half a flat
+    pub skipped: Vec<i64>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (id, discrepancy) in self.discrepancies.iter() {
+            writeln!(f, "Game {}: {}", id, discrepancy)?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(
+                f,
+                "{} games with a komi of half a flat were skipped",
+                self.skipped.len()
+            )?;
+        }
+        write!(
+            f,
+            "{} of {} games disagree with the rules",
+            self.discrepancies.len(),
+            self.games
+        )
+    }
+}
+
+///Whether a result replayed on the board agrees with the one recorded by the server. Flat counts
+/// are not recorded, and games which were resigned, timed out, drawn by agreement or abandoned must
+/// not have ended on the board.
+fn consistent(stored: Victory, replayed: Victory) -> bool {
+    match (stored, replayed) {
+        (Victory::WhiteFlat(_), Victory::WhiteFlat(_)) => true,
+        (Victory::BlackFlat(_), Victory::BlackFlat(_)) => true,
+        (Victory::Draw, Victory::Neither) => true,
+        (Victory::WhiteOther, Victory::Neither) => true,
+        (Victory::BlackOther, Victory::Neither) => true,
+        (Victory::WhiteFlat(_), _) | (Victory::BlackFlat(_), _) => false,
+        _ => stored == replayed,
+    }
+}
+
+///Replays the moves of an archived game under the standard rules with the reserves and komi of the
+/// given setup, returning how it disagrees with the recorded result, if it does
+pub fn replay(config: GameConfig, moves: &[Move], stored: Victory) -> Option<Discrepancy> {
+    let mut game = make_standard_game(config);
+    for (ply, m) in moves.iter().enumerate() {
+        if let Err(e) = game.do_ply(m.clone()) {
+            return Some(Discrepancy::IllegalMove {
+                ply,
+                m: m.clone(),
+                reason: e.to_string(),
+            });
+        }
+    }
+    let replayed = game.result();
+    if consistent(stored, replayed) {
+        None
+    } else {
+        Some(Discrepancy::ResultMismatch { stored, replayed })
+    }
+}
+
+///Replays every game admitted by the filter on the given number of threads while the archive is
+/// read on the calling one. Games set up in a way our rules cannot play are skipped, and only
+/// errors of the database itself stop the verification.
+pub fn verify(archive: &Archive, filter: &Filter, threads: usize) -> Result<Report, ArchiveError> {
+    let (work_sender, work) = mpsc::sync_channel::<(i64, GameConfig, Vec<Move>, Victory)>(64);
+    let work = Arc::new(Mutex::new(work));
+    let (result_sender, results) = mpsc::channel();
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let work = Arc::clone(&work);
+            let result_sender = result_sender.clone();
+            thread::spawn(move || loop {
+                // The lock is released before replaying so that the workers run in parallel
+                let next = work.lock().unwrap().recv();
+                let (id, config, moves, stored) = match next {
+                    Ok(game) => game,
+                    Err(_) => break,
+                };
+                if let Some(discrepancy) = replay(config, &moves, stored) {
+                    let _ = result_sender.send((id, discrepancy));
+                }
+            })
+        })
+        .collect();
+    // Only the workers hold the queue, so sending fails rather than blocks once they all panic
+    drop(work);
+    drop(result_sender);
+
+    let mut report = Report::default();
+    for game in archive.games(filter)? {
+        report.games += 1;
+        match game {
+            Ok((info, moves, stored)) => match info.config() {
+                Some(config) => {
+                    if work_sender.send((info.id, config, moves, stored)).is_err() {
+                        break;
+                    }
+                }
+                None => report.skipped.push(info.id),
+            },
+            Err(ArchiveError::Malformed { id, column }) => report
+                .discrepancies
+                .push((id, Discrepancy::Malformed(column))),
+            Err(ArchiveError::Decode { id, error }) => report
+                .discrepancies
+                .push((id, Discrepancy::Undecodable(error))),
+            Err(e) => return Err(e),
+        }
+    }
+    drop(work_sender);
+    let joined: Vec<_> = workers.into_iter().map(|worker| worker.join()).collect();
+    if joined.iter().any(|j| j.is_err()) {
+        return Err(ArchiveError::ReplayPanicked);
+    }
+    report.discrepancies.extend(results.iter());
+    report.discrepancies.sort_by_key(|(id, _)| *id);
+    Ok(report)
+}
@@ -0,0 +1,39 @@
+use crate::game::rules::Rules;
+use crate::game::state::*;
+
+///Counts the leaf nodes of the move tree of the given depth from the current position. Positions
+/// where the game has ended are leaves regardless of the remaining depth. This is the standard way
+/// of checking a move generator against other implementations.
+pub fn perft(rules: &mut dyn Rules, depth: u32) -> u64 {
+    if depth == 0 || rules.check_win() != Victory::Neither {
+        return 1;
+    }
+    let moves = rules.legal_moves();
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    let mut nodes = 0;
+    for m in moves {
+        rules.make_move(m).expect("Generated moves are legal");
+        nodes += perft(rules, depth - 1);
+        rules.unmake_move();
+    }
+    nodes
+}
+
+///Performs perft, listing the node count below each legal move from the current position so that
+/// disagreements with another implementation can be narrowed down to a single move
+pub fn perft_divide(rules: &mut dyn Rules, depth: u32) -> Vec<(Move, u64)> {
+    let mut divide = Vec::new();
+    if depth == 0 || rules.check_win() != Victory::Neither {
+        return divide;
+    }
+    for m in rules.legal_moves() {
+        rules
+            .make_move(m.clone())
+            .expect("Generated moves are legal");
+        divide.push((m, perft(rules, depth - 1)));
+        rules.unmake_move();
+    }
+    divide
+}
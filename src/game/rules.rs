@@ -1,10 +1,10 @@
-use failure::{bail, Error};
-
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::fmt;
 use std::rc::Rc;
 
 use crate::game::state::*;
+use crate::game::zobrist;
 
 pub struct Reached {
     north: bool,
@@ -13,41 +13,237 @@ pub struct Reached {
     west: bool,
 }
 
-pub trait Rules {
+///The reason a move is illegal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    /// The square placed on or moved from is not on the board
+    OutOfBounds,
+    /// A piece was placed on a square which already holds one
+    Occupied,
+    /// Only flats may be placed during the opening
+    NonFlatInOpening,
+    NoCapstoneLeft,
+    /// A flat or wall was placed by a player with no stones left in reserve
+    NoStonesLeft,
+    /// The square moved from holds no pieces
+    EmptySource,
+    /// The stack moved is topped by an opponent's piece
+    NotYourStack,
+    StackMoveInOpening,
+    /// A stack move without any drops
+    NoDrops,
+    /// A stack move would drop pieces past the edge of the board
+    ThrowOffBoard,
+    /// A stack move passes over or ends on a wall without crushing it
+    BlockedByWall,
+    /// A stack move passes over or ends on a capstone
+    BlockedByCapstone,
+    /// A capstone crushing a wall must be the only piece dropped on it
+    CapstoneMustCrushAlone,
+    /// Only a capstone can crush a wall
+    CannotCrushWithoutCap,
+    /// More pieces were picked up than the carry limit allows
+    CarryLimitExceeded,
+    /// More pieces were picked up than the source stack holds
+    StackTooShort,
+    /// A stack move drops no pieces on one of the squares it passes
+    EmptyDrop,
+    /// The drops of a stack move do not add up to the pieces picked up
+    DropCountMismatch,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            MoveError::OutOfBounds => "Square is off the board",
+            MoveError::Occupied => "Square is already occupied",
+            MoveError::NonFlatInOpening => "Only flats may be placed in the opening",
+            MoveError::NoCapstoneLeft => "Player has no capstones left",
+            MoveError::NoStonesLeft => "Player has no stones left",
+            MoveError::EmptySource => "Moving from an empty tile",
+            MoveError::NotYourStack => "Cannot move a stack you don't control",
+            MoveError::StackMoveInOpening => "Cannot move a stack in the opening",
+            MoveError::NoDrops => "A stack move must drop at least one piece",
+            MoveError::ThrowOffBoard => "Target tile(s) off the board",
+            MoveError::BlockedByWall => "Cannot move onto a wall",
+            MoveError::BlockedByCapstone => "Cannot move onto a capstone",
+            MoveError::CapstoneMustCrushAlone => "The capstone must step alone to crush walls",
+            MoveError::CannotCrushWithoutCap => "Cannot crush a wall without a capstone",
+            MoveError::CarryLimitExceeded => "Cannot carry more pieces than the carry limit",
+            MoveError::StackTooShort => "Cannot pick up more pieces than the stack holds",
+            MoveError::EmptyDrop => "At least one piece must be dropped on each square",
+            MoveError::DropCountMismatch => "The pieces dropped must add up to those picked up",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+///Rule sets are Send so that games can be hosted on any thread of a server
+pub trait Rules: Send {
     /// Returns true if a given move is legal but does not execute the move
     fn legal_move(&self, m: Move) -> bool {
+        self.check_move(&m).is_ok()
+    }
+    /// Returns why a given move is illegal, if it is, without executing the move
+    fn check_move(&self, m: &Move) -> Result<(), MoveError> {
         match m {
-            Move::Place(kind, (row, col), _ptn) => {
+            Move::Place(kind, square) => {
                 let color = self.current_color();
-                let piece = Piece::new(color, kind);
-                self.legal_place_move(piece, row, col).is_ok()
+                let piece = Piece::new(color, *kind);
+                self.legal_place_move(piece, *square)
+            }
+            Move::Throw(count, source, dir, vec) => self
+                .legal_stack_move(*count, *source, *dir, vec)
+                .map(|_| ()),
+        }
+    }
+    /// Generates every legal move available to the active player
+    fn legal_moves(&self) -> Vec<Move> {
+        let size = self.get_size();
+        let mut moves = Vec::new();
+        for row in 0..size {
+            for col in 0..size {
+                moves.append(&mut self.legal_moves_from(Square::new(row, col)));
+            }
+        }
+        moves
+    }
+    /// Generates every legal move that either places a piece on the given square or throws the
+    /// stack standing on it. Candidates are judged by legal_place_move and legal_stack_move, so
+    /// rule variants overriding those are respected here as well.
+    fn legal_moves_from(&self, square: Square) -> Vec<Move> {
+        let state = self.get_state();
+        let mut moves = Vec::new();
+        if state.out_of_bounds(square.row, square.col) {
+            return moves;
+        }
+        let color = self.current_color();
+        for kind in [PieceKind::Flat, PieceKind::Wall, PieceKind::Cap].iter() {
+            let piece = Piece::new(color, *kind);
+            if self.legal_place_move(piece, square).is_ok() {
+                moves.push(Move::Place(*kind, square));
             }
-            Move::Throw(source, dir, vec, _ptn) => self.legal_stack_move(source, dir, &vec).is_ok(),
         }
+        let height = state.get_tile(square.row, square.col).stack.len();
+        let carry = std::cmp::min(height, self.carry_limit() as usize) as u8;
+        for dir in Direction::ALL.iter() {
+            let distance = match dir {
+                Direction::Up => state.size - 1 - square.row,
+                Direction::Down => square.row,
+                Direction::Left => square.col,
+                Direction::Right => state.size - 1 - square.col,
+            };
+            for count in 1..=carry {
+                for vec in drop_sequences(count, distance) {
+                    if self.legal_stack_move(count, square, *dir, &vec).is_ok() {
+                        moves.push(Move::Throw(count, square, *dir, vec));
+                    }
+                }
+            }
+        }
+        moves
     }
     /// Attempts to make a move returning Ok if successful or Error if unsuccessful
-    fn make_move(&mut self, m: Move) -> Result<(), Error> {
-        let ptn = match m {
-            Move::Place(kind, (row, col), ptn) => {
+    fn make_move(&mut self, m: Move) -> Result<(), MoveError> {
+        match &m {
+            Move::Place(kind, square) => {
                 let color = self.current_color();
-                let piece = Piece::new(color, kind);
-                self.legal_place_move(piece, row, col)?;
-                self.unchecked_place_move(piece, row, col);
-                ptn
-            }
-            Move::Throw(source, dir, vec, ptn) => {
-                let res = self.legal_stack_move(source, dir, &vec)?;
-                self.unchecked_stack_move(source, dir, vec, res);
-                ptn
+                let piece = Piece::new(color, *kind);
+                self.legal_place_move(piece, *square)?;
+                self.unchecked_place_move(piece, *square);
+            }
+            Move::Throw(count, source, dir, vec) => {
+                let res = self.legal_stack_move(*count, *source, *dir, vec)?;
+                if self.unchecked_stack_move(*source, *dir, vec, res) {
+                    let state = self.get_mut_state();
+                    state.crushes.push(state.history.len());
+                }
             }
-        };
-        self.get_mut_state().add_notation(ptn);
+        }
+        self.get_mut_state().history.push(m);
         Ok(())
     }
 
-    fn unchecked_place_move(&mut self, piece: Piece, row: u8, col: u8) {
+    /// Reverts the most recently made move, returning it, or None if no move has been made
+    fn unmake_move(&mut self) -> Option<Move> {
+        let state = self.get_mut_state();
+        let m = state.history.pop()?;
+        let crushed = state.crushes.last() == Some(&state.history.len());
+        if crushed {
+            state.crushes.pop();
+        }
+        match &m {
+            Move::Place(_, square) => self.unchecked_unplace_move(*square),
+            Move::Throw(_, source, dir, vec) => {
+                self.unchecked_unstack_move(*source, *dir, vec, crushed)
+            }
+        }
+        Some(m)
+    }
+
+    /// Lifts the piece off the given square and returns it to its owner's reserve
+    fn unchecked_unplace_move(&mut self, square: Square) {
+        let state = self.get_mut_state();
+        let piece = state
+            .get_mut_tile(square.row, square.col)
+            .stack
+            .pop()
+            .unwrap();
+        let height = state.get_tile(square.row, square.col).stack.len();
+        state.hash ^= zobrist::piece_key(square, height, piece) ^ zobrist::side_key();
+        state.hash ^= zobrist::reserve_key(state.get_player(piece.color));
+        match piece.kind {
+            PieceKind::Cap => {
+                state.get_mut_player(piece.color).caps += 1;
+            }
+            _ => {
+                state.get_mut_player(piece.color).pieces += 1;
+            }
+        }
+        state.hash ^= zobrist::reserve_key(state.get_player(piece.color));
+    }
+
+    /// Picks the dropped pieces back up along the path of a throw and restacks them on the source,
+    /// standing the flattened wall under the last drop back up if the throw crushed it
+    fn unchecked_unstack_move(
+        &mut self,
+        source: Square,
+        dir: Direction,
+        vec: &[u8],
+        crushed: bool,
+    ) {
+        let state = self.get_mut_state();
+        // Only legal moves are recorded, so the path is on the board
+        let path: Vec<_> = (0..=vec.len())
+            .map(|i| source.shift(dir, i as u8).unwrap())
+            .collect();
+        state.hash ^= path
+            .iter()
+            .fold(zobrist::side_key(), |h, sq| h ^ state.stack_hash(*sq));
+        let mut carried = Vec::new();
+        for (square, val) in path[1..].iter().zip(vec.iter()) {
+            let tile = state.get_mut_tile(square.row, square.col);
+            let length = tile.stack.len();
+            carried.append(&mut tile.stack.split_off(length - *val as usize));
+        }
+        if crushed {
+            let last = path[path.len() - 1];
+            let tile = state.get_mut_tile(last.row, last.col);
+            let length = tile.stack.len();
+            tile.stack[length - 1].kind = PieceKind::Wall;
+        }
+        state
+            .get_mut_tile(source.row, source.col)
+            .add_pieces(carried);
+        state.hash ^= path.iter().fold(0, |h, sq| h ^ state.stack_hash(*sq));
+    }
+
+    fn unchecked_place_move(&mut self, piece: Piece, square: Square) {
         let state = self.get_mut_state();
         let color = piece.color;
+        state.hash ^= zobrist::reserve_key(state.get_player(color));
         match piece.kind {
             PieceKind::Cap => {
                 state.get_mut_player(color).caps -= 1;
@@ -56,158 +252,161 @@ pub trait Rules {
                 state.get_mut_player(color).pieces -= 1;
             }
         }
-        state.get_mut_tile(row, col).add_piece(piece);
+        state.hash ^= zobrist::reserve_key(state.get_player(color));
+        let height = state.get_tile(square.row, square.col).stack.len();
+        state.hash ^= zobrist::piece_key(square, height, piece) ^ zobrist::side_key();
+        state.get_mut_tile(square.row, square.col).add_piece(piece);
     }
 
-    fn legal_place_move(&self, piece: Piece, row: u8, col: u8) -> Result<(), Error> {
+    fn legal_place_move(&self, piece: Piece, square: Square) -> Result<(), MoveError> {
         let state = self.get_state();
         // Check valid square for placing a piece
-        if state.out_of_bounds(row, col) || !state.is_empty(row, col) {
-            bail!("Invalid square selected");
+        if state.out_of_bounds(square.row, square.col) {
+            return Err(MoveError::OutOfBounds);
         }
-        if let PieceKind::Cap = piece.kind {
-            if !state.has_capstone(piece.color) {
-                bail!("Player has no capstones left");
+        if !state.is_empty(square.row, square.col) {
+            return Err(MoveError::Occupied);
+        }
+        if self.is_opening() && piece.kind != PieceKind::Flat {
+            return Err(MoveError::NonFlatInOpening);
+        }
+        match piece.kind {
+            PieceKind::Cap if !state.has_capstone(piece.color) => {
+                return Err(MoveError::NoCapstoneLeft)
+            }
+            PieceKind::Flat | PieceKind::Wall if !state.has_stones(piece.color) => {
+                return Err(MoveError::NoStonesLeft)
             }
+            _ => {}
         }
         Ok(())
     }
 
+    /// Executes a throw judged legal by legal_stack_move, flattening a wall on the last square.
+    /// Returns whether a wall was crushed.
     fn unchecked_stack_move(
         &mut self,
-        source: (u8, u8, u8),
-        dir: char,
-        vec: Vec<u8>,
-        res: (u8, u8, u8),
-    ) {
+        source: Square,
+        dir: Direction,
+        vec: &[u8],
+        res: (u8, Square),
+    ) -> bool {
         let state = self.get_mut_state();
-        let (sum, mut x, mut y) = res;
+        let (sum, mut target) = res;
+        let path: Vec<_> = (0..=vec.len())
+            .map(|i| source.shift(dir, i as u8).unwrap())
+            .collect();
+        state.hash ^= path
+            .iter()
+            .fold(zobrist::side_key(), |h, sq| h ^ state.stack_hash(*sq));
         // Now that we've found the move valid, we execute it, in reverse
-        let source_len = state.get_mut_tile(source.1, source.2).stack.len();
+        let source_len = state.get_mut_tile(source.row, source.col).stack.len();
         let mut source_vec = state
-            .get_mut_tile(source.1, source.2)
+            .get_mut_tile(source.row, source.col)
             .stack
             .split_off(source_len - sum as usize);
+        let crushed = match state.get_mut_tile(target.row, target.col).stack.last_mut() {
+            Some(top) if top.kind == PieceKind::Wall => {
+                top.kind = PieceKind::Flat;
+                true
+            }
+            _ => false,
+        };
 
         for val in vec.iter().rev() {
             let val = *val as usize;
             let length = source_vec.len();
             state
-                .get_mut_tile(x, y)
+                .get_mut_tile(target.row, target.col)
                 .add_pieces(source_vec.drain(length - val..length).collect());
-            match dir {
-                //Optimize into one match later, if necessary
-                '+' => x -= 1,
-                '-' => x += 1,
-                '<' => y += 1,
-                '>' => y -= 1,
-                _ => unreachable!(), // Already checked
+            if let Some(next) = target.shift(dir.opposite(), 1) {
+                target = next;
             }
         }
+        state.hash ^= path.iter().fold(0, |h, sq| h ^ state.stack_hash(*sq));
+        crushed
     }
 
     fn legal_stack_move(
         &self,
-        source: (u8, u8, u8),
-        dir: char,
+        count: u8,
+        source: Square,
+        dir: Direction,
         vec: &[u8],
-    ) -> Result<(u8, u8, u8), Error> {
+    ) -> Result<(u8, Square), MoveError> {
         let state = self.get_state();
-        if source.0 > state.size || state.out_of_bounds(source.1, source.2) || vec.len() < 1 {
-            bail!("Invalid move signature for this board");
+        if state.out_of_bounds(source.row, source.col) {
+            return Err(MoveError::OutOfBounds);
+        }
+        if count > self.carry_limit() {
+            return Err(MoveError::CarryLimitExceeded);
+        }
+        if vec.is_empty() {
+            return Err(MoveError::NoDrops);
+        }
+        if vec.contains(&0) {
+            return Err(MoveError::EmptyDrop);
+        }
+        //Summed wide so that malformed drops cannot overflow
+        if vec.iter().map(|val| *val as u32).sum::<u32>() != count as u32 {
+            return Err(MoveError::DropCountMismatch);
         }
-        let source_tile = state.get_tile(source.1, source.2);
+        let source_tile = state.get_tile(source.row, source.col);
         if source_tile.is_empty() {
-            bail!("Moving from an empty tile");
+            return Err(MoveError::EmptySource);
         }
         if self.current_color() != source_tile.top_unchecked().color {
-            bail!("Cannot move a stack you don't control");
+            return Err(MoveError::NotYourStack);
         }
         if self.is_opening() {
-            bail!("Cannot move a stack in the opening");
+            return Err(MoveError::StackMoveInOpening);
         }
-        let mut x = source.1;
-        let mut y = source.2;
-
-        //Check if the farthest target is on the board, usize is Copy so no problems here
-        match dir {
-            '+' => x += vec.len() as u8,
-            '-' => {
-                if x as usize >= vec.len() {
-                    x -= vec.len() as u8
-                } else {
-                    bail!("Target tile(s) off the board");
-                }
-            }
-            '<' => {
-                if y as usize >= vec.len() {
-                    y -= vec.len() as u8
-                } else {
-                    bail!("Target tile(s) off the board");
-                }
-            }
-            '>' => y += vec.len() as u8,
-            _ => bail!("Unknown movement direction"), //Invalid
+        if count as usize > source_tile.stack.len() {
+            return Err(MoveError::StackTooShort);
         }
-        if state.out_of_bounds(x, y) {
-            bail!("Target tile(s) off the board");
-        }
-        //Delay reset x, y
+
+        //Check if the farthest target is on the board
+        let last = match source.shift(dir, vec.len() as u8) {
+            Some(last) if !state.out_of_bounds(last.row, last.col) => last,
+            _ => return Err(MoveError::ThrowOffBoard),
+        };
         //Check the last position in the throw vector for special case wall crush
-        let (last_x, last_y) = {
-            let last_tile = state.get_tile(x, y);
-            //We assume the vec to be in normal stack order.
-            if !last_tile.stack.is_empty() {
-                match last_tile.top_unchecked().kind {
-                    PieceKind::Wall => {
-                        //Check for valid crush
-                        if let PieceKind::Cap =
-                            state.get_tile(source.1, source.2).top().unwrap().kind
-                        {
-                            if vec[vec.len() - 1] != 1 {
-                                bail!(
-                                    "The capstone must step alone\
-                                     to crush walls"
-                                );
-                            }
-                        } else {
-                            bail!("Cannot crush a wall without a capstone");
+        let last_tile = state.get_tile(last.row, last.col);
+        //We assume the vec to be in normal stack order.
+        if !last_tile.stack.is_empty() {
+            match last_tile.top_unchecked().kind {
+                PieceKind::Wall => {
+                    //Check for valid crush
+                    if let PieceKind::Cap = source_tile.top_unchecked().kind {
+                        if vec[vec.len() - 1] != 1 {
+                            return Err(MoveError::CapstoneMustCrushAlone);
                         }
+                    } else {
+                        return Err(MoveError::CannotCrushWithoutCap);
                     }
-                    PieceKind::Cap => {
-                        bail!("Cannot end throw on a capstone");
-                    }
-                    _ => {}
                 }
+                PieceKind::Cap => {
+                    return Err(MoveError::BlockedByCapstone);
+                }
+                _ => {}
             }
-            (x, y)
-        };
-        x = source.1;
-        y = source.2;
-        let mut sum = 0;
-        for val in vec.iter() {
-            match dir {
-                // Optimize into one match later, if necessary
-                '+' => x += 1,
-                '-' => x -= 1,
-                '<' => y -= 1,
-                '>' => y += 1,
-                _ => unreachable!(), // Already checked
-            }
-            if !(x == last_x && y == last_y) {
+        }
+        for i in 0..vec.len() {
+            let square = source.shift(dir, i as u8 + 1).unwrap();
+            if square != last {
                 // Already checked the last tile
-                match state.get_tile(x, y).top() {
+                match state.get_tile(square.row, square.col).top() {
                     Some(p) => match p.kind {
                         PieceKind::Flat => {}
-                        _ => bail!("Cannot move through a wall or capstone"),
+                        PieceKind::Wall => return Err(MoveError::BlockedByWall),
+                        PieceKind::Cap => return Err(MoveError::BlockedByCapstone),
                     },
                     None => {}
                 }
             }
-            sum += *val;
         }
 
-        Ok((sum, x, y))
+        Ok((count, last))
     }
     /// Whether or not the game is in the opening phase, the phase of the game
     /// where the rules behave differently than normal. In a standard game this
@@ -234,8 +433,22 @@ pub trait Rules {
             }
         }
     }
+    /// The color of the player whose turn it is. Unlike current_color this is not swapped during
+    /// the opening.
+    fn side_to_move(&self) -> Color {
+        if self.current_ply().is_multiple_of(2) {
+            Color::White
+        } else {
+            Color::Black
+        }
+    }
     fn check_win(&self) -> Victory {
-        let last_to_move = self.current_color();
+        // The player who made the last move wins if it completes roads for both players
+        let last_to_move = if self.current_ply() % 2 == 1 {
+            Color::White
+        } else {
+            Color::Black
+        };
         let discovered: Rc<RefCell<HashSet<(usize, usize)>>> =
             Rc::new(RefCell::new(HashSet::new()));
         //This iter generation may be able to be optimized, we'll see
@@ -313,8 +526,8 @@ pub trait Rules {
         } else if black_road {
             return Victory::BlackRoad;
         }
-        //Out of pieces check for both players
-        if self.get_state().player1.pieces == 0 || self.get_state().player2.pieces == 0 {
+        //Out of pieces check for both players, counting capstones still in reserve
+        if self.get_state().player1.is_exhausted() || self.get_state().player2.is_exhausted() {
             return self.flat_game();
         }
         //Board fill check
@@ -374,6 +587,11 @@ pub trait Rules {
                 return false;
             }
         };
+        if white ^ white_start {
+            //If this tile isn't the same color as what we're investigating, leave it undiscovered
+            // so that a search for the other color can still start from or pass through it
+            return false;
+        }
         //Add this to the discovered set, then drop the mutability from the scope
         {
             let mut m_set = set.borrow_mut();
@@ -382,10 +600,6 @@ pub trait Rules {
             }
             m_set.insert(node);
         }
-        if white ^ white_start {
-            //If this tile isn't the same color as what we're investigating
-            return false;
-        }
 
         //Start flag setting/checking
         let last = (self.get_size() - 1) as usize;
@@ -481,19 +695,42 @@ pub trait Rules {
             Color::Black => &self.get_state().player2,
         }
     }
+    /// The number of flats added to black's count in a flat game
+    fn komi(&self) -> u32 {
+        0
+    }
     fn get_size(&self) -> u8 {
         self.get_state().size
     }
+    /// The most pieces a stack move may pick up, which is the board size unless a variant says
+    /// otherwise
+    fn carry_limit(&self) -> u8 {
+        self.get_size()
+    }
     fn get_state(&self) -> &State;
     fn get_mut_state(&mut self) -> &mut State;
-    fn add_notation(&mut self, string: String) {
-        self.get_mut_state().notation.push(string);
-    }
 
     /// The 0-indexed ply count of the game
     fn current_ply(&self) -> u32;
 }
 
+///Every way of dropping count pieces over at most max_len consecutive squares, leaving at least
+/// one piece on each square passed
+fn drop_sequences(count: u8, max_len: u8) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+    if max_len == 0 {
+        return out;
+    }
+    out.push(vec![count]);
+    for first in 1..count {
+        for mut rest in drop_sequences(count - first, max_len - 1) {
+            rest.insert(0, first);
+            out.push(rest);
+        }
+    }
+    out
+}
+
 pub struct StandardRules {
     pub state: State,
 }
@@ -514,7 +751,7 @@ impl Rules for StandardRules {
     }
 
     fn current_ply(&self) -> u32 {
-        self.get_state().notation.len() as u32
+        self.get_state().ply()
     }
 }
 
@@ -533,7 +770,11 @@ impl Rules for KomiRules {
     }
 
     fn current_ply(&self) -> u32 {
-        self.get_state().notation.len() as u32
+        self.get_state().ply()
+    }
+
+    fn komi(&self) -> u32 {
+        self.komi
     }
 
     fn flat_game(&self) -> Victory {
@@ -563,4 +804,4 @@ impl Rules for KomiRules {
         }
         return Victory::Draw;
     }
-}
\ No newline at end of file
+}
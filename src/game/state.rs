@@ -1,27 +1,155 @@
+use failure::{bail, Error};
 use ndarray::Array2;
 
+use crate::game::zobrist;
 use std::fmt;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Color {
     White,
     Black,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Color {
+    pub fn opposite(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum PieceKind {
     Flat,
     Wall,
     Cap,
 }
 
-#[derive(Debug)]
+///A square on the board. Row 0 is rank 1 and column 0 is file a, so a1 is the origin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Square {
+    pub row: u8,
+    pub col: u8,
+}
+
+impl Square {
+    pub fn new(row: u8, col: u8) -> Square {
+        Square { row, col }
+    }
+
+    ///The square the given distance away in a direction, or None if that would pass the a file or
+    /// first rank. Squares beyond the far edges of the board must be checked by the caller.
+    pub fn shift(self, dir: Direction, distance: u8) -> Option<Square> {
+        match dir {
+            Direction::Up => self
+                .row
+                .checked_add(distance)
+                .map(|row| Square::new(row, self.col)),
+            Direction::Down => self
+                .row
+                .checked_sub(distance)
+                .map(|row| Square::new(row, self.col)),
+            Direction::Left => self
+                .col
+                .checked_sub(distance)
+                .map(|col| Square::new(self.row, col)),
+            Direction::Right => self
+                .col
+                .checked_add(distance)
+                .map(|col| Square::new(self.row, col)),
+        }
+    }
+}
+
+impl fmt::Display for Square {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", (b'a' + self.col) as char, self.row + 1)
+    }
+}
+
+///The direction of a stack move, Up being toward higher ranks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
+
+    ///The ptn symbol of the direction, e.g. + for Up
+    pub fn symbol(self) -> char {
+        match self {
+            Direction::Up => '+',
+            Direction::Down => '-',
+            Direction::Left => '<',
+            Direction::Right => '>',
+        }
+    }
+
+    pub fn from_symbol(symbol: char) -> Option<Direction> {
+        match symbol {
+            '+' => Some(Direction::Up),
+            '-' => Some(Direction::Down),
+            '<' => Some(Direction::Left),
+            '>' => Some(Direction::Right),
+            _ => None,
+        }
+    }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Move {
-    Place(PieceKind, (u8, u8), String),
-    Throw((u8, u8, u8), char, Vec<u8>, String), //Source then direction and quantity then ptn
+    Place(PieceKind, Square),
+    Throw(u8, Square, Direction, Vec<u8>), //Pickup count, source, direction then drops
 }
 
-#[derive(Debug, PartialEq)]
+///Formats the move as ptn. The pickup count and drops are elided when a single piece is moved.
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Move::Place(kind, square) => {
+                let prefix = match kind {
+                    PieceKind::Flat => "",
+                    PieceKind::Wall => "S",
+                    PieceKind::Cap => "C",
+                };
+                write!(f, "{}{}", prefix, square)
+            }
+            Move::Throw(count, source, dir, vec) => {
+                if *count > 1 {
+                    write!(f, "{}", count)?;
+                }
+                write!(f, "{}{}", source, dir.symbol())?;
+                if *count > 1 {
+                    for val in vec.iter() {
+                        write!(f, "{}", val)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Victory {
     Neither,
     WhiteFlat(u32),
@@ -122,43 +250,241 @@ impl fmt::Display for Tile {
 }
 
 ///Game state contains the board and the players. For reference, a is the first column, 1 is the
-/// first row. Let player1 be white and player2 be black
+/// first row. Let player1 be white and player2 be black. Positions loaded midgame, e.g. from TPS,
+/// record the ply they were loaded at in start_ply. The Zobrist hash of the position is kept up to
+/// date by moves made through the rules; states edited directly must recompute it. The indices of
+/// the moves in history on which a capstone flattened a wall are kept in crushes so that undoing
+/// them can stand the wall back up.
 pub struct State {
     pub board: Array2<Tile>,
     pub size: u8,
     pub player1: Player,
     pub player2: Player,
-    pub notation: Vec<String>,
+    pub history: Vec<Move>,
+    pub crushes: Vec<usize>,
+    pub start_ply: u32,
+    pub hash: u64,
+}
+
+///The stones and capstones each player starts with on a board of the given size, or None if the
+/// size is not played
+pub fn standard_reserves(size: u8) -> Option<(i32, i32)> {
+    match size {
+        3 => Some((10, 0)),
+        4 => Some((15, 0)),
+        5 => Some((21, 1)),
+        6 => Some((30, 1)),
+        7 => Some((40, 2)),
+        8 => Some((50, 2)),
+        _ => None,
+    }
 }
 
 impl State {
-    pub fn new(size: u8) -> State {
-        let (pieces, caps) = {
-            match size {
-                3 => (10, 0),
-                4 => (15, 0),
-                5 => (21, 1),
-                6 => (30, 1),
-                8 => (50, 2),
-                _ => (21, 1), //Default 5
-            }
+    ///Creates an empty board with the standard reserves for its size, or an error if the size is
+    /// not between 3 and 8
+    pub fn try_new(size: u8) -> Result<State, Error> {
+        let (pieces, caps) = match standard_reserves(size) {
+            Some(reserves) => reserves,
+            None => bail!("Unsupported board size {}", size),
         };
-        State {
-            board: Array2::default((size as usize, size as usize)),
-            size,
-            player1: Player::new(Color::White, pieces, caps),
-            player2: Player::new(Color::Black, pieces, caps),
-            notation: Vec::new(),
-        }
+        let player1 = Player::new(Color::White, pieces, caps);
+        let player2 = Player::new(Color::Black, pieces, caps);
+        Ok(State::new_with_players(size, player1, player2))
+    }
+    ///Creates an empty board with the standard reserves for its size. A convenience for sizes known
+    /// to be supported, which panics if the size is not between 3 and 8; use try_new otherwise.
+    pub fn new(size: u8) -> State {
+        State::try_new(size).unwrap_or_else(|e| panic!("{}", e))
     }
+    ///Creates an empty board with the given players. The size must be between 3 and 8, as the
+    /// Zobrist keys cover no larger boards.
     pub fn new_with_players(size: u8, player1: Player, player2: Player) -> State {
-        State {
+        let mut state = State {
             board: Array2::default((size as usize, size as usize)),
             size,
             player1,
             player2,
-            notation: Vec::new(),
+            history: Vec::new(),
+            crushes: Vec::new(),
+            start_ply: 0,
+            hash: 0,
+        };
+        state.hash = state.compute_hash();
+        state
+    }
+
+    ///Parses a TPS string such as `x3,12,2S/x,22C,1/x,1,x 1 5` into a state. Reserves are derived
+    /// by removing the pieces on the board from the standard reserves for the board size.
+    pub fn from_tps(tps: &str) -> Result<State, Error> {
+        let fields: Vec<&str> = tps.split_whitespace().collect();
+        if fields.len() != 3 {
+            bail!("TPS must contain a board, the player to move and the move number");
+        }
+        let rows: Vec<&str> = fields[0].split('/').collect();
+        let size = rows.len();
+        if !(3..=8).contains(&size) {
+            bail!("Unsupported board size {}", size);
+        }
+        let mut state = State::new(size as u8);
+        for (i, row) in rows.iter().enumerate() {
+            let row_index = size - i - 1;
+            let mut col: usize = 0;
+            for square in row.split(',') {
+                if let Some(count) = square.strip_prefix('x') {
+                    let empty = match count {
+                        "" => 1,
+                        count => match count.parse::<usize>() {
+                            Ok(count) if count > 0 => count,
+                            _ => bail!("Invalid empty square count {}", square),
+                        },
+                    };
+                    col = match col.checked_add(empty) {
+                        Some(col) if col <= size => col,
+                        _ => bail!("Row {} has more than {} squares", row_index + 1, size),
+                    };
+                    continue;
+                }
+                if col >= size {
+                    bail!("Row {} has more than {} squares", row_index + 1, size);
+                }
+                let tile = &mut state.board[(row_index, col)];
+                let mut chars = square.chars().peekable();
+                while let Some(ch) = chars.next() {
+                    let color = match ch {
+                        '1' => Color::White,
+                        '2' => Color::Black,
+                        _ => bail!("Invalid stack {}", square),
+                    };
+                    let kind = match chars.peek() {
+                        Some('S') => PieceKind::Wall,
+                        Some('C') => PieceKind::Cap,
+                        _ => PieceKind::Flat,
+                    };
+                    match kind {
+                        PieceKind::Flat => {}
+                        _ => {
+                            chars.next();
+                            if chars.peek().is_some() {
+                                bail!("Only the top of stack {} may be a wall or capstone", square);
+                            }
+                        }
+                    }
+                    tile.add_piece(Piece::new(color, kind));
+                }
+                if tile.is_empty() {
+                    bail!("Empty stack in row {}", row_index + 1);
+                }
+                col += 1;
+            }
+            if col != size {
+                bail!("Row {} does not have {} squares", row_index + 1, size);
+            }
+        }
+        let player = match fields[1] {
+            "1" => 0,
+            "2" => 1,
+            p => bail!("Invalid player to move {}", p),
+        };
+        let start_ply = match fields[2].parse::<u32>() {
+            Ok(num) if num > 0 => (num - 1)
+                .checked_mul(2)
+                .and_then(|ply| ply.checked_add(player)),
+            _ => None,
+        };
+        state.start_ply = match start_ply {
+            Some(ply) => ply,
+            None => bail!("Invalid move number {}", fields[2]),
+        };
+        for piece in state.board.iter().flat_map(|t| t.stack.iter()) {
+            let player = match piece.color {
+                Color::White => &mut state.player1,
+                Color::Black => &mut state.player2,
+            };
+            match piece.kind {
+                PieceKind::Cap => player.caps -= 1,
+                _ => player.pieces -= 1,
+            }
+        }
+        for player in [&state.player1, &state.player2].iter() {
+            if player.pieces < 0 || player.caps < 0 {
+                bail!("More pieces on the board than a player has in reserve");
+            }
+        }
+        state.hash = state.compute_hash();
+        Ok(state)
+    }
+
+    ///Serializes the position into a TPS string
+    pub fn to_tps(&self) -> String {
+        let size = self.size as usize;
+        let mut rows = Vec::new();
+        for i in (0..size).rev() {
+            let mut squares = Vec::new();
+            let mut empty = 0;
+            for j in 0..size {
+                let tile = &self.board[(i, j)];
+                if tile.is_empty() {
+                    empty += 1;
+                    continue;
+                }
+                if empty > 0 {
+                    squares.push(empty_squares(empty));
+                    empty = 0;
+                }
+                let mut square: String = tile
+                    .stack
+                    .iter()
+                    .map(|p| match p.color {
+                        Color::White => '1',
+                        Color::Black => '2',
+                    })
+                    .collect();
+                match tile.top_unchecked().kind {
+                    PieceKind::Wall => square.push('S'),
+                    PieceKind::Cap => square.push('C'),
+                    PieceKind::Flat => {}
+                }
+                squares.push(square);
+            }
+            if empty > 0 {
+                squares.push(empty_squares(empty));
+            }
+            rows.push(squares.join(","));
+        }
+        let ply = self.ply();
+        format!("{} {} {}", rows.join("/"), ply % 2 + 1, ply / 2 + 1)
+    }
+
+    ///Computes the Zobrist hash of the position from scratch, covering every piece on the board,
+    /// both reserves and the side to move
+    pub fn compute_hash(&self) -> u64 {
+        let mut hash = zobrist::reserve_key(&self.player1) ^ zobrist::reserve_key(&self.player2);
+        if self.ply() % 2 == 1 {
+            hash ^= zobrist::side_key();
         }
+        for row in 0..self.size {
+            for col in 0..self.size {
+                hash ^= self.stack_hash(Square::new(row, col));
+            }
+        }
+        hash
+    }
+
+    ///The combined Zobrist keys of every piece in the stack on a square
+    pub fn stack_hash(&self, square: Square) -> u64 {
+        self.get_tile(square.row, square.col)
+            .stack
+            .iter()
+            .enumerate()
+            .fold(0, |hash, (height, piece)| {
+                hash ^ zobrist::piece_key(square, height, *piece)
+            })
+    }
+
+    /// The 0-indexed ply of the position, counting plies made before it was loaded
+    pub fn ply(&self) -> u32 {
+        self.start_ply + self.history.len() as u32
     }
 
     /// True if the input square is off the board
@@ -186,6 +512,10 @@ impl State {
         self.get_player(color).caps > 0
     }
 
+    pub fn has_stones(&self, color: Color) -> bool {
+        self.get_player(color).pieces > 0
+    }
+
     pub fn get_tile(&self, row: u8, col: u8) -> &Tile {
         self.board.get((row as usize, col as usize)).unwrap()
     }
@@ -208,15 +538,16 @@ impl State {
         }
     }
 
-    pub fn add_notation(&mut self, ptn: String) {
-        self.notation.push(ptn);
+    ///The ptn of every move made since the state was created
+    pub fn notation(&self) -> Vec<String> {
+        self.history.iter().map(|m| m.to_string()).collect()
     }
 }
 
 impl fmt::Display for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut string = String::from("");
-        if self.notation.len() % 2 == 0 {
+        if self.ply() % 2 == 0 {
             string.push_str("White to move: \n");
         } else {
             string.push_str("Black to move: \n");
@@ -241,6 +572,14 @@ impl fmt::Display for State {
     }
 }
 
+fn empty_squares(count: usize) -> String {
+    if count == 1 {
+        String::from("x")
+    } else {
+        format!("x{}", count)
+    }
+}
+
 pub struct Player {
     pub color: Color,
     pub pieces: i32,
@@ -259,4 +598,13 @@ impl Player {
     pub fn has_capstone(&self) -> bool {
         self.caps > 0
     }
-}
\ No newline at end of file
+
+    pub fn has_stones(&self) -> bool {
+        self.pieces > 0
+    }
+
+    ///A player who has placed every stone and capstone ends the game
+    pub fn is_exhausted(&self) -> bool {
+        !self.has_stones() && !self.has_capstone()
+    }
+}
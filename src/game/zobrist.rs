@@ -0,0 +1,81 @@
+use crate::game::state::*;
+
+/// Largest supported board size
+const MAX_SIZE: usize = 8;
+/// Stack heights and reserve counts beyond this share keys with lower ones
+const MAX_HEIGHT: usize = 128;
+
+lazy_static! {
+    static ref KEYS: Keys = Keys::new();
+}
+
+///Pseudorandom keys for every feature of a position, generated from a fixed seed so hashes are
+/// stable between runs and can be stored, e.g. in a game database
+struct Keys {
+    pieces: Vec<u64>,
+    reserves: Vec<u64>,
+    black_to_move: u64,
+}
+
+impl Keys {
+    fn new() -> Keys {
+        let mut seed = 0x5EED_7A4B_0A8D_u64;
+        // splitmix64
+        let mut next = || {
+            seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        };
+        let pieces = (0..MAX_SIZE * MAX_SIZE * MAX_HEIGHT * 6)
+            .map(|_| next())
+            .collect();
+        let reserves = (0..2 * 2 * MAX_HEIGHT).map(|_| next()).collect();
+        Keys {
+            pieces,
+            reserves,
+            black_to_move: next(),
+        }
+    }
+}
+
+fn piece_index(piece: Piece) -> usize {
+    let kind = match piece.kind {
+        PieceKind::Flat => 0,
+        PieceKind::Wall => 1,
+        PieceKind::Cap => 2,
+    };
+    match piece.color {
+        Color::White => kind,
+        Color::Black => kind + 3,
+    }
+}
+
+fn color_index(color: Color) -> usize {
+    match color {
+        Color::White => 0,
+        Color::Black => 1,
+    }
+}
+
+///The key of a piece at the given height, 0 being the bottom, of a stack on a square. Squares
+/// beyond the largest board have no key and panic.
+pub fn piece_key(square: Square, height: usize, piece: Piece) -> u64 {
+    let square = square.row as usize * MAX_SIZE + square.col as usize;
+    let index = (square * MAX_HEIGHT + height % MAX_HEIGHT) * 6 + piece_index(piece);
+    KEYS.pieces[index]
+}
+
+///The key of a player's reserve of stones and capstones
+pub fn reserve_key(player: &Player) -> u64 {
+    let base = color_index(player.color) * 2 * MAX_HEIGHT;
+    let pieces = player.pieces.max(0) as usize % MAX_HEIGHT;
+    let caps = player.caps.max(0) as usize % MAX_HEIGHT;
+    KEYS.reserves[base + pieces] ^ KEYS.reserves[base + MAX_HEIGHT + caps]
+}
+
+///The key toggled whenever the side to move changes
+pub fn side_key() -> u64 {
+    KEYS.black_to_move
+}
@@ -3,10 +3,13 @@ extern crate ndarray;
 extern crate lazy_static;
 extern crate regex;
 
-#[cfg(test)]
+#[cfg(feature = "sqlite")]
 extern crate sqlite;
 
+pub mod client;
+pub mod engine;
 pub mod game;
+pub mod server;
 
 use failure::Error;
 
@@ -15,12 +18,13 @@ mod tests {
 
     use super::*;
     use game::*;
-    use sqlite::Value;
 
+    #[cfg(feature = "sqlite")]
     #[test]
     fn display_test() {
-        let (moves, _res, size) = get_playtak_game("games_anon.db", 220000);
-        let r = StandardRules::new(State::new(size as u8));
+        let archive = game::database::playtak::Archive::open("games_anon.db").unwrap();
+        let (info, moves, _res) = archive.game(220000).unwrap().unwrap();
+        let r = StandardRules::new(State::new(info.size));
         let mut game = Game::new(Box::new(r));
         for m in moves.into_iter() {
             assert!(game.do_ply(m).is_ok());
@@ -89,23 +93,159 @@ mod tests {
     }
 
     #[test]
-    fn test_many_playtak_games() {
-        for _id in 220000..220586 {
-            //Verified 150k - 220586
-            let (mut moves, res, size) = get_playtak_game("games_anon.db", 220000);
-            let r = StandardRules::new(State::new(size as u8));
-            let mut game = Game::new(Box::new(r));
-            let last = moves.pop().unwrap();
-            for m in moves.into_iter() {
-                let attempt_move = game.do_ply(m);
-                assert!(attempt_move.is_ok());
-                assert_eq!(Victory::Neither, attempt_move.unwrap());
+    fn test_move_errors() {
+        let mut game = make_standard_game(GameConfig::new(5));
+        let error = |game: &Game, ptn: &str| {
+            let m = ptn_move(ptn).unwrap();
+            let rules = &game.rules;
+            match m {
+                Move::Place(kind, square) => {
+                    let piece = Piece::new(rules.current_color(), kind);
+                    rules.legal_place_move(piece, square).unwrap_err()
+                }
+                Move::Throw(count, source, dir, vec) => rules
+                    .legal_stack_move(count, source, dir, &vec)
+                    .unwrap_err(),
+            }
+        };
+        assert_eq!(error(&game, "Sa1"), MoveError::NonFlatInOpening);
+        let moves = vec!["a5", "a1", "b1", "c1", "b2", "c2", "b3", "c3", "Cb4", "Cb5"];
+        for m in moves {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        assert_eq!(error(&game, "b4+"), MoveError::BlockedByCapstone);
+        assert_eq!(error(&game, "a1"), MoveError::Occupied);
+        assert_eq!(error(&game, "e1<"), MoveError::EmptySource);
+        for m in vec!["a3", "c3<", "b4-", "Sd3"] {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        assert_eq!(error(&game, "3b3>111"), MoveError::BlockedByWall);
+        assert_eq!(error(&game, "3b3>12"), MoveError::CapstoneMustCrushAlone);
+        assert_eq!(error(&game, "d3-"), MoveError::NotYourStack);
+        assert_eq!(error(&game, "a3<"), MoveError::ThrowOffBoard);
+        assert_eq!(error(&game, "Ce1"), MoveError::NoCapstoneLeft);
+        assert_eq!(error(&game, "6b3+6"), MoveError::CarryLimitExceeded);
+        assert_eq!(error(&game, "f1"), MoveError::OutOfBounds);
+        assert_eq!(error(&game, "4b3+4"), MoveError::StackTooShort);
+        let stack_error = |count, vec: &[u8]| {
+            let b3 = Square::new(2, 1);
+            game.rules
+                .legal_stack_move(count, b3, Direction::Up, vec)
+                .unwrap_err()
+        };
+        assert_eq!(stack_error(3, &[1, 0, 2]), MoveError::EmptyDrop);
+        assert_eq!(stack_error(3, &[1, 1]), MoveError::DropCountMismatch);
+        assert_eq!(stack_error(2, &[255, 3]), MoveError::DropCountMismatch);
+        assert_eq!(stack_error(2, &[1; 300]), MoveError::DropCountMismatch);
+        let failure = game.do_ply(ptn_move("d3-").unwrap()).unwrap_err();
+        assert_eq!(
+            failure.downcast_ref::<MoveError>(),
+            Some(&MoveError::NotYourStack)
+        );
+        for m in vec!["d2", "e5"] {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        assert_eq!(error(&game, "d2+"), MoveError::CannotCrushWithoutCap);
+    }
+
+    #[test]
+    fn test_carry_limit() {
+        struct Carry {
+            state: State,
+            limit: u8,
+        }
+        impl Rules for Carry {
+            fn get_state(&self) -> &State {
+                &self.state
+            }
+            fn get_mut_state(&mut self) -> &mut State {
+                &mut self.state
+            }
+            fn current_ply(&self) -> u32 {
+                self.state.ply()
+            }
+            fn carry_limit(&self) -> u8 {
+                self.limit
             }
-            let attempt_move = game.do_ply(last);
-            assert!(attempt_move.is_ok());
-            let victory_string = format!("{}", attempt_move.unwrap());
-            assert_eq!(victory_string, res);
         }
+        let mut game = Game::new(Box::new(Carry {
+            state: State::new(5),
+            limit: 2,
+        }));
+        let moves = vec!["a5", "a1", "b1", "c1", "b2", "c2", "b3", "c3", "Cb4", "Cb5"];
+        for m in moves.into_iter().chain(vec!["a3", "c3<", "b4-", "Sd3"]) {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        assert!(game.legal_move(ptn_move("2b3+2").unwrap()));
+        assert!(!game.legal_move(ptn_move("3b3+3").unwrap()));
+        assert!(game.parse_move("2b3+2").is_ok());
+        assert_eq!(
+            game.parse_move("3b3+3"),
+            Err(MoveParseError::CarryLimitExceeded(3))
+        );
+        assert!(game
+            .rules
+            .legal_moves_from(Square::new(2, 1))
+            .iter()
+            .all(|m| matches!(m, Move::Throw(count, ..) if *count <= 2)));
+        // The parser follows a limit above the board size as well
+        let game = Game::new(Box::new(Carry {
+            state: State::new(5),
+            limit: 7,
+        }));
+        assert_eq!(game.parse_move("7b2+7"), ptn_move("7b2+7"));
+    }
+
+    #[test]
+    fn test_legal_moves() {
+        let mut game = make_standard_game(GameConfig::new(5));
+        let ptn_set = |game: &Game| -> Vec<String> {
+            game.legal_moves().iter().map(|m| m.to_string()).collect()
+        };
+        assert!(ptn_set(&game).contains(&String::from("a1")));
+        assert!(!ptn_set(&game).contains(&String::from("Sa1")));
+        assert_eq!(game.legal_moves().len(), 25);
+        let moves = vec!["a5", "a1", "b1", "c1", "b2", "c2", "b3", "c3", "Cb4", "Cb5"];
+        for m in moves {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        game.do_ply(ptn_move("a3").unwrap()).unwrap();
+        game.do_ply(ptn_move("c3<").unwrap()).unwrap();
+        game.do_ply(ptn_move("b4-").unwrap()).unwrap();
+        game.do_ply(ptn_move("Sd3").unwrap()).unwrap();
+        let generated = game.legal_moves();
+        for m in generated.iter() {
+            assert!(game.legal_move(m.clone()));
+        }
+        let ptns = ptn_set(&game);
+        for legal in &["e1", "Se1", "3b3+3", "3b3<3", "3b3>21", "b2-", "a3+"] {
+            assert!(ptns.contains(&String::from(*legal)), "{} missing", legal);
+        }
+        for illegal in &[
+            "b4+", "3b3+12", "3b3>111", "3b3>12", "d3-", "a3<", "Sd3", "Ce1",
+        ] {
+            assert!(
+                !ptns.contains(&String::from(*illegal)),
+                "{} generated",
+                illegal
+            );
+        }
+        let from_b3 = game.rules.legal_moves_from(Square::new(2, 1));
+        assert!(from_b3
+            .iter()
+            .all(|m| matches!(m, Move::Throw(_, Square { row: 2, col: 1 }, _, _))));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_many_playtak_games() {
+        use game::database::playtak::*;
+        //Verified 150k - 220586
+        let archive = Archive::open("games_anon.db").unwrap();
+        let filter = Filter::new().with_ids(220000, 220585);
+        let report = verify(&archive, &filter, 4).unwrap();
+        assert!(report.games > 0);
+        assert!(report.is_clean(), "{}", report);
     }
 
     #[test]
@@ -116,7 +256,7 @@ mod tests {
         let game_moves = ptn_moves
             .into_iter()
             .map(|m| game::ptn_move(m).expect("Valid ptn"));
-        let mut game = make_standard_game(5);
+        let mut game = make_standard_game(GameConfig::new(5));
         for m in game_moves {
             let res = game.do_ply(m);
             assert!(res.is_ok());
@@ -127,28 +267,1182 @@ mod tests {
         let res = game.do_ply(crush);
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), Victory::Neither);
+        // The crushed wall is flattened and stays flat once the capstone moves on
+        let kinds = |game: &Game| -> Vec<PieceKind> {
+            game.get_board()[(4, 1)]
+                .stack
+                .iter()
+                .map(|p| p.kind)
+                .collect()
+        };
+        assert_eq!(kinds(&game), vec![PieceKind::Flat, PieceKind::Cap]);
+        game.do_ply(game::ptn_move("e4").unwrap()).unwrap();
+        game.do_ply(game::ptn_move("b5>").unwrap()).unwrap();
+        assert_eq!(kinds(&game), vec![PieceKind::Flat]);
+        // Undoing the crush stands the wall back up
+        game.undo_ply();
+        game.undo_ply();
+        game.undo_ply();
+        assert_eq!(kinds(&game), vec![PieceKind::Wall]);
+        assert_eq!(game.get_state().hash, game.get_state().compute_hash());
+    }
+
+    #[test]
+    fn test_undo() {
+        let ptn_moves = vec![
+            "a5", "a1", "b1", "Sb5", "Cc2", "e5", "b3", "b2", "b3-", "d4", "c2<", "c5", "3b2+111",
+        ];
+        let mut game = make_standard_game(GameConfig::new(5));
+        let mut snapshots = Vec::new();
+        for m in ptn_moves.iter() {
+            snapshots.push(game.get_state().to_string());
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        while let Some(m) = game.undo_ply() {
+            assert_eq!(snapshots.pop().unwrap(), game.get_state().to_string());
+            assert!(game.legal_move(m));
+        }
+        assert!(snapshots.is_empty());
+        assert!(game.get_state().history.is_empty());
+        assert!(game.get_board().iter().all(|t| t.is_empty()));
+        assert_eq!(game.get_state().player1.pieces, 21);
+        assert_eq!(game.get_state().player2.caps, 1);
+    }
+
+    #[test]
+    fn test_tps() {
+        let tps = "x3,12,2S/x,22C,1,x,1/x2,121,x2/2,x4/x4,1C 2 9";
+        let state = State::from_tps(tps).unwrap();
+        assert_eq!(state.size, 5);
+        assert_eq!(state.ply(), 17);
+        assert_eq!(state.to_tps(), tps);
+        assert_eq!(state.get_tile(3, 1).stack.len(), 2);
+        assert_eq!(state.player1.pieces, 21 - 5);
+        assert_eq!(state.player1.caps, 0);
+        assert_eq!(state.player2.pieces, 21 - 5);
+        assert_eq!(state.player2.caps, 0);
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        assert!(game.next_piece_color() == Color::Black);
+        assert!(!game.legal_move(ptn_move("Ca1").unwrap()));
+        game.do_ply(ptn_move("2b4>2").unwrap()).unwrap();
+        assert_eq!(
+            game.get_state().to_tps(),
+            "x3,12,2S/x2,122C,x,1/x2,121,x2/2,x4/x4,1C 1 10"
+        );
+
+        let start = make_standard_game(GameConfig::new(6));
+        assert_eq!(start.get_state().to_tps(), "x6/x6/x6/x6/x6/x6 1 1");
+        for bad in &[
+            "x3/x3 1 1",
+            "x4/x4/x4/x4 3 1",
+            "x3/x3/x2,3 1 1",
+            "x3/x3/1S2,x2 1 1",
+            "x3/x3/x3 1 0",
+            "x3/x3/x3 2 4294967295",
+            "x18446744073709551615,x/x3/x3 1 1",
+        ] {
+            assert!(State::from_tps(bad).is_err(), "{}", bad);
+        }
+        let state = State::from_tps("x3/x3/x3 2 2147483648").unwrap();
+        assert_eq!(state.start_ply, u32::MAX);
+    }
+
+    #[test]
+    fn test_write_ptn() {
+        let mut game = make_standard_game(GameConfig::new(5));
+        let moves = vec![
+            "a5", "e1", "b1", "e2", "b2", "e3", "b3", "e4", "b4", "d5", "b5",
+        ];
+        for m in moves {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        let tags = [
+            ("Player1", "alice"),
+            ("Player2", "bob"),
+            ("Date", "2019.08.25"),
+        ];
+        let ptn = game::database::write_ptn(&game, &tags);
+        let expected = "[Player1 \"alice\"]\n[Player2 \"bob\"]\n[Date \"2019.08.25\"]\n\
+                        [Size \"5\"]\n[Result \"R-0\"]\n[Komi \"0\"]\n[Flats \"21\"]\n\
+                        [Caps \"1\"]\n\n1. a5 e1\n2. b1 e2\n3. b2 e3\n4. b3 e4\n5. b4 d5\n\
+                        6. b5 R-0\n";
+        assert_eq!(ptn, expected);
+        let read = game::database::read_formatted_ptn(&ptn).unwrap();
+        assert_eq!(read.moves.len(), 11);
+        assert_eq!(read.tag("Player2"), Some("bob"));
+        assert_eq!(read.result, Some(String::from("R-0")));
+
+        let state = State::from_tps("x5/x5/x5/x5/2,x3,1 2 2").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        game.do_ply(ptn_move("c3").unwrap()).unwrap();
+        game.do_ply(ptn_move("c4").unwrap()).unwrap();
+        let ptn = game::database::write_ptn(&game, &[("Event", "The \"Open\" \\ 2019")]);
+        assert!(ptn.starts_with("[Event \"The \\\"Open\\\" \\\\ 2019\"]\n[Size \"5\"]\n"));
+        assert!(ptn.contains("[TPS \"x5/x5/x5/x5/2,x3,1 2 2\"]\n"));
+        assert!(ptn.ends_with("\n\n2. -- c3\n3. c4\n"));
+        // Reading the document back reproduces the tags and the game from its starting position
+        let read = game::database::read_formatted_ptn(&ptn).unwrap();
+        assert_eq!(read.tag("Event"), Some("The \"Open\" \\ 2019"));
+        let mut replayed = read.new_game().unwrap();
+        for m in read.moves {
+            replayed.do_ply(m).unwrap();
+        }
+        assert_eq!(replayed.get_state().to_tps(), game.get_state().to_tps());
+
+        // A position set up before the first move is written out as well
+        let state = State::from_tps("x5/x5/x5/x5/2,x3,1 1 1").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        game.do_ply(ptn_move("c3").unwrap()).unwrap();
+        let ptn = game::database::write_ptn(&game, &[]);
+        assert!(ptn.contains("[TPS \"x5/x5/x5/x5/2,x3,1 1 1\"]\n"));
+        let read = game::database::read_formatted_ptn(&ptn).unwrap();
+        let mut replayed = read.new_game().unwrap();
+        replayed.do_ply(read.moves[0].clone()).unwrap();
+        assert_eq!(replayed.get_state().to_tps(), game.get_state().to_tps());
+
+        let mut game = make_standard_game(GameConfig::new(5));
+        game.abort().unwrap();
+        assert!(game::database::write_ptn(&game, &[]).ends_with("[Caps \"1\"]\n\n1/2-1/2\n"));
+    }
+
+    #[test]
+    fn test_read_ptn() {
+        use game::database::{read_formatted_ptn, PtnErrorKind};
+        let ptn = "[Site \"PlayTak.com\"]\n[Size \"5\"]\n\n\
+                   {Opening comment\nover two lines} 1. a5 e1 2. b1 {a comment} e2'\n\
+                   3. b2? e3!\n4.\nb3 e4 5. b4'' d5 6. b5 R-0\n";
+        let read = read_formatted_ptn(ptn).unwrap();
+        assert_eq!(read.tags.len(), 2);
+        assert_eq!(read.tag("Site"), Some("PlayTak.com"));
+        assert_eq!(read.result, Some(String::from("R-0")));
+        let mut game = read.new_game().unwrap();
+        let mut last = Victory::Neither;
+        for m in read.moves {
+            last = game.do_ply(m).unwrap();
+        }
+        assert_eq!(last, Victory::WhiteRoad);
+
+        let draw = read_formatted_ptn("[TPS \"x3/x3/x3 2 2\"]\n2. -- a1 1/2-1/2").unwrap();
+        assert_eq!(draw.moves.len(), 1);
+        assert_eq!(draw.new_game().unwrap().get_state().ply(), 3);
+        // Komi applies to games starting from a TPS position as well
+        let komi = read_formatted_ptn("[TPS \"x3/x3/x3 2 2\"]\n[Komi \"2\"]\n2. -- a1").unwrap();
+        assert_eq!(komi.new_game().unwrap().rules.komi(), 2);
+
+        let error = |ptn: &str| read_formatted_ptn(ptn).unwrap_err();
+        let e = error("[Size \"5\"]\n1. a1 {unclosed\n");
+        assert_eq!((e.line, e.column), (2, 7));
+        assert_eq!(e.kind, PtnErrorKind::UnterminatedComment);
+        let e = error("1. a1 b9");
+        assert_eq!((e.line, e.column), (1, 7));
+        assert_eq!(e.kind, PtnErrorKind::InvalidMove(String::from("b9")));
+        assert_eq!(
+            error("1. a1 0-R b2").kind,
+            PtnErrorKind::MoveAfterResult(String::from("b2"))
+        );
+        assert_eq!(error("[Size 5]").kind, PtnErrorKind::MalformedTag);
+        // With a starting position the moves are played, so crush markers and legality are checked
+        let crush = "[TPS \"x5/x5/x,2S,x3/x,1C,x3/x5 1 3\"]\n3. ";
+        assert_eq!(
+            read_formatted_ptn(&format!("{}b2+*", crush))
+                .unwrap()
+                .moves
+                .len(),
+            1
+        );
+        let e = error(&format!("{}b2+", crush));
+        assert_eq!((e.line, e.column), (2, 4));
+        assert_eq!(e.kind, PtnErrorKind::InvalidMove(String::from("b2+")));
+        assert_eq!(
+            error("[Size \"5\"]\n1. a1 a1").kind,
+            PtnErrorKind::InvalidMove(String::from("a1"))
+        );
+        let e = error("\n[Size \"12\"]");
+        assert_eq!((e.line, e.column), (2, 1));
+        assert_eq!(e.kind, PtnErrorKind::InvalidTagValue(String::from("Size")));
+        assert_eq!(
+            error("[TPS \"x3/x3/x3 1 3000000000\"]").kind,
+            PtnErrorKind::InvalidTagValue(String::from("TPS"))
+        );
     }
 
-    ///Reads a single game from a playtak database, returning the moves and the end of game state, e.g.
-    /// F-0. This is used for testing purposes only and, as such, data is assumed to be valid.
-    fn get_playtak_game(file: &str, id: i64) -> (Vec<Move>, String, usize) {
-        let connection = sqlite::open(file).unwrap();
-        let mut cursor = connection
-            .prepare("SELECT size, notation, result FROM games WHERE id = ?")
+    #[test]
+    fn test_playtak_encoding() {
+        use game::database::{decode_playtak_notation, encode_playtak_game, encode_playtak_move};
+        let ptn_moves = vec![
+            "a5", "a1", "b1", "Sb5", "Cc2", "e5", "b3", "b2", "b3-", "d4", "c2<", "c5", "3b2+111",
+        ];
+        let mut game = make_standard_game(GameConfig::new(5));
+        for m in ptn_moves.iter() {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+        }
+        let encoded = encode_playtak_game(&game).unwrap();
+        assert!(encoded.starts_with("P A5,P A1,P B1,P B5 W,P C2 C,"));
+        assert!(encoded.ends_with(",M C2 B2 1,P C5,M B2 B5 1 1 1"));
+        let decoded: Vec<_> = decode_playtak_notation(&encoded)
             .unwrap()
-            .cursor();
-        cursor.bind(&[Value::Integer(id)]).unwrap();
-
-        if let Some(row) = cursor.next().unwrap() {
-            let size = row[0].as_integer().unwrap() as usize;
-            let server_notation: &str = row[1].as_string().unwrap();
-            return (
-                game::database::decode_playtak_notation(server_notation),
-                String::from(row[2].as_string().unwrap()),
-                size,
+            .iter()
+            .map(|m| encode_playtak_move(m).unwrap())
+            .collect();
+        assert_eq!(decoded.join(","), encoded);
+        let wide = decode_playtak_notation("P H8,P G1 C,M G1 H1 1,M H8 H6 1 1").unwrap();
+        let wide: Vec<_> = wide.iter().map(|m| m.to_string()).collect();
+        assert_eq!(wide, vec!["h8", "Cg1", "g1>", "2h8-11"]);
+        assert_eq!(decode_playtak_notation(""), Ok(Vec::new()));
+        for (notation, index) in &[
+            ("P A1,P I1", 1),
+            ("P A1,P B2,M A1", 2),
+            ("P A1 X", 0),
+            ("P A1,,P B2", 1),
+            ("M A1 B2 1", 0),
+            ("M A1 A3 1 0", 0),
+            ("M A1 A2 999", 0),
+        ] {
+            let error = decode_playtak_notation(notation).unwrap_err();
+            assert_eq!(error.index, *index, "{}", notation);
+        }
+        let encode = |ptn| encode_playtak_move(&ptn_move(ptn).unwrap());
+        assert_eq!(encode("4e4<22"), Some(String::from("M E4 C4 2 2")));
+        assert_eq!(encode("2d3-2"), Some(String::from("M D3 D2 2")));
+        // Throws past the edge of the largest board cannot be named
+        assert_eq!(encode("2a1<11"), None);
+        assert_eq!(encode("3h8+111"), None);
+        assert_eq!(encode("2e1-2"), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_playtak_archive() {
+        use game::database::playtak::*;
+        let path = std::env::temp_dir().join(format!("archive_{}.db", std::process::id()));
+        assert!(matches!(
+            Archive::open(&path),
+            Err(ArchiveError::NotFound(_))
+        ));
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE games (id INTEGER PRIMARY KEY, date INT, size INT,
+                    player_white VARCHAR(20), player_black VARCHAR(20), notation TEXT,
+                    result VARCHAR(10), timertime INT, timerinc INT, rating_white INT,
+                    rating_black INT, komi INT, pieces INT, capstones INT);
+                INSERT INTO games VALUES
+                    (1, 1000, 5, 'alice', 'bob', 'P A1,P E5', '0-1', 600, 10, 1500, 1400, 0, -1,
+                        -1),
+                    (2, 2000, 6, 'bob', 'carol', 'P A1,P F6', '1/2-1/2', 900, 0, 1600, 1700, 4,
+                        30, 1),
+                    (3, 3000, 5, 'carol', 'alice', 'P A1,P Z9', 'R-0', 600, 10, 1800, 1650, 0,
+                        -1, -1),
+                    (4, 4000, 5, 'alice', 'carol', 'P A1,P E5', 'F-0', 600, 10, 1800, 1750, 3,
+                        -1, -1);",
+            )
+            .unwrap();
+        let archive = Archive::open(&path).unwrap();
+        let ids = |filter: Filter| -> Vec<i64> {
+            archive
+                .games(&filter)
+                .unwrap()
+                .map(|game| match game {
+                    Ok((info, _, _)) => info.id,
+                    Err(ArchiveError::Decode { id, .. }) => -id,
+                    Err(e) => panic!("{}", e),
+                })
+                .collect()
+        };
+        assert_eq!(ids(Filter::new()), vec![1, 2, -3, 4]);
+        assert_eq!(ids(Filter::new().with_size(6)), vec![2]);
+        assert_eq!(ids(Filter::new().with_player("bob")), vec![1, 2]);
+        assert_eq!(ids(Filter::new().with_dates(2000, 3000)), vec![2, -3]);
+        assert_eq!(
+            ids(Filter::new().with_result(Victory::WhiteFlat(9))),
+            vec![4]
+        );
+        assert_eq!(ids(Filter::new().with_min_rating(1650)), vec![-3, 4]);
+        let filter = Filter::new().with_player("alice").with_size(5);
+        assert_eq!(archive.count(&filter).unwrap(), 3);
+
+        let (info, moves, result) = archive.game(2).unwrap().unwrap();
+        assert_eq!(info.white, "bob");
+        assert_eq!((info.time, info.increment), (900, 0));
+        assert_eq!(info.config(), Some(GameConfig::new(6).with_komi(2)));
+        assert_eq!(result, Victory::Draw);
+        assert_eq!(
+            moves,
+            vec![ptn_move("a1").unwrap(), ptn_move("f6").unwrap()]
+        );
+        let (info, _, _) = archive.game(1).unwrap().unwrap();
+        assert_eq!(info.config(), Some(GameConfig::new(5)));
+        let (info, _, _) = archive.game(4).unwrap().unwrap();
+        assert_eq!(info.config(), None);
+        assert!(archive.game(5).unwrap().is_none());
+        match archive.game(3) {
+            Err(ArchiveError::Decode { id: 3, error }) => assert_eq!(error.index, 1),
+            _ => panic!("Game 3 should not decode"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_playtak_verify() {
+        use game::database::playtak::*;
+        use game::database::DecodeError;
+        let path = std::env::temp_dir().join(format!("verify_{}.db", std::process::id()));
+        let road = "P E5,P A1,P B1,P E4,P C1,P E3,P D1,P E2,P E1";
+        let rows = [
+            (1, road, "R-0"),
+            (2, road, "0-R"),
+            (3, "P A1,P A1", "0-0"),
+            (4, "P A1,P B9", "0-0"),
+            (5, "P A1,P E1,P B1", "1-0"),
+            (6, &format!("{},P C2", road), "R-0"),
+            (7, "P A1,P E1,P B1", "1/2-1/2"),
+        ];
+        // White runs out of its two stones ahead on flats but behind once black's komi is added
+        let setups = [(8, "0-F", 4), (9, "F-0", 0), (10, "0-F", 3)];
+        let connection = sqlite::open(&path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE games (id INTEGER PRIMARY KEY, date INT, size INT,
+                    player_white VARCHAR(20), player_black VARCHAR(20), notation TEXT,
+                    result VARCHAR(10), timertime INT, timerinc INT, rating_white INT,
+                    rating_black INT, komi INT, pieces INT, capstones INT)",
+            )
+            .unwrap();
+        for (id, notation, result) in rows.iter() {
+            connection
+                .execute(format!(
+                    "INSERT INTO games VALUES ({}, 0, 5, 'a', 'b', '{}', '{}', 0, 0, 0, 0, 0, -1, \
+                     -1)",
+                    id, notation, result
+                ))
+                .unwrap();
+        }
+        for (id, result, komi) in setups.iter() {
+            connection
+                .execute(format!(
+                    "INSERT INTO games VALUES ({}, 0, 5, 'a', 'b', 'P A1,P E5,P B1', '{}', 0, 0, \
+                     0, 0, {}, 2, 0)",
+                    id, result, komi
+                ))
+                .unwrap();
+        }
+        let archive = Archive::open(&path).unwrap();
+        let report = verify(&archive, &Filter::new(), 3).unwrap();
+        assert_eq!(report.games, 10);
+        assert_eq!(report.skipped, vec![10]);
+        let ids: Vec<_> = report.discrepancies.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2, 3, 4, 6], "{}", report);
+        assert_eq!(
+            report.discrepancies[0].1,
+            Discrepancy::ResultMismatch {
+                stored: Victory::BlackRoad,
+                replayed: Victory::WhiteRoad
+            }
+        );
+        assert!(matches!(
+            report.discrepancies[1].1,
+            Discrepancy::IllegalMove { ply: 1, .. }
+        ));
+        assert!(matches!(
+            report.discrepancies[2].1,
+            Discrepancy::Undecodable(DecodeError { index: 1, .. })
+        ));
+        assert!(matches!(
+            report.discrepancies[3].1,
+            Discrepancy::IllegalMove { ply: 9, .. }
+        ));
+        let summary = report.to_string();
+        assert!(summary.starts_with("Game 2: Recorded 0-R but replayed R-0\n"));
+        assert!(summary.ends_with(
+            "1 games with a komi of half a flat were skipped\n4 of 10 games disagree with the rules"
+        ));
+        assert_eq!(
+            verify(&archive, &Filter::new().with_ids(5, 7), 1)
+                .unwrap()
+                .discrepancies
+                .len(),
+            1
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_move_display() {
+        for ptn in &["a1", "Sb2", "Cc3", "e5<", "3b3>12", "5h8-311", "2a1+2"] {
+            assert_eq!(ptn_move(ptn).unwrap().to_string(), *ptn);
+        }
+        assert_eq!(ptn_move("sb2").unwrap().to_string(), "Sb2");
+        assert_eq!(ptn_move("1e5<1").unwrap().to_string(), "e5<");
+        assert_eq!(
+            ptn_move("3b3>12").unwrap(),
+            Move::Throw(3, Square::new(2, 1), Direction::Right, vec![1, 2])
+        );
+        assert_eq!(Square::new(0, 0).shift(Direction::Down, 1), None);
+        assert_eq!(
+            Square::new(0, 0).shift(Direction::Up, 2),
+            Some(Square::new(2, 0))
+        );
+    }
+
+    #[test]
+    fn test_ptn_validation() {
+        assert_eq!(ptn_move("3b3>").unwrap().to_string(), "3b3>3");
+        for bad in &["", "i1", "a9", "3a1", "Sa1>", "a1*", "a1>>"] {
+            assert_eq!(ptn_move(bad), Err(MoveParseError::Syntax), "{}", bad);
+        }
+        assert_eq!(ptn_move("0a1>"), Err(MoveParseError::EmptyPickup));
+        assert_eq!(
+            ptn_move("9a1>333"),
+            Err(MoveParseError::CarryLimitExceeded(9))
+        );
+        assert_eq!(ptn_move("3a1>102"), Err(MoveParseError::ZeroDrop));
+        assert_eq!(ptn_move("3b3>12*"), Err(MoveParseError::CrushMismatch));
+        assert_eq!(
+            ptn_move("3b3>11"),
+            Err(MoveParseError::CountMismatch {
+                picked: 3,
+                dropped: 2
+            })
+        );
+        assert_eq!(
+            ptn_move("2b3>111"),
+            Err(MoveParseError::CountMismatch {
+                picked: 2,
+                dropped: 3
+            })
+        );
+
+        let ptn_moves = vec![
+            "a5", "a1", "b1", "Sb5", "Cc2", "e5", "b3", "b2", "b3-", "d4", "c2<", "c5",
+        ];
+        let mut game = make_standard_game(GameConfig::new(5));
+        for m in ptn_moves.iter() {
+            game.do_ply(game.parse_move(m).unwrap()).unwrap();
+        }
+        assert_eq!(
+            game.parse_move("6b2+111111"),
+            Err(MoveParseError::CarryLimitExceeded(6))
+        );
+        assert_eq!(
+            game.parse_move("3b2+111"),
+            Err(MoveParseError::CrushMismatch)
+        );
+        assert_eq!(game.parse_move("b2>*"), Err(MoveParseError::CrushMismatch));
+        assert_eq!(game.parse_move("3b2+111*"), ptn_move("3b2+111"));
+        assert!(game.parse_move("b2>").is_ok());
+    }
+
+    #[test]
+    fn test_zobrist() {
+        let ptn_moves = vec![
+            "a5", "a1", "b1", "Sb5", "Cc2", "e5", "b3", "b2", "b3-", "d4", "c2<", "c5", "3b2+111",
+        ];
+        let mut game = make_standard_game(GameConfig::new(5));
+        let start = game.get_state().hash;
+        let mut hashes = vec![start];
+        for m in ptn_moves.iter() {
+            game.do_ply(ptn_move(m).unwrap()).unwrap();
+            let state = game.get_state();
+            assert_eq!(state.hash, state.compute_hash());
+            assert!(!hashes.contains(&state.hash));
+            hashes.push(state.hash);
+        }
+        while game.undo_ply().is_some() {
+            hashes.pop();
+            assert_eq!(game.get_state().hash, *hashes.last().unwrap());
+        }
+        assert_eq!(game.get_state().hash, start);
+
+        let transpose = |order: &[&str]| {
+            let mut game = make_standard_game(GameConfig::new(5));
+            for m in order {
+                game.do_ply(ptn_move(m).unwrap()).unwrap();
+            }
+            let tps = game.get_state().to_tps();
+            assert_eq!(State::from_tps(&tps).unwrap().hash, game.get_state().hash);
+            game.get_state().hash
+        };
+        let first = transpose(&["a1", "e5", "b1", "d1", "c1"]);
+        assert_eq!(first, transpose(&["a1", "e5", "c1", "d1", "b1"]));
+        assert_ne!(first, transpose(&["a1", "e5", "c1", "d1", "Sb1"]));
+        assert_ne!(first, transpose(&["a1", "e5", "c1", "b1", "d1"]));
+    }
+
+    #[test]
+    fn test_alphabeta() {
+        use engine::alphabeta::search;
+        use engine::{SearchLimits, WIN_SCORE};
+        use std::time::{Duration, Instant};
+
+        let state = State::from_tps("1,1,1,x/2,2,2,x/x4/x4 1 4").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        let result = search(&mut *game.rules, SearchLimits::depth(3));
+        assert_eq!(result.best_move.unwrap().to_string(), "d4");
+        assert_eq!(result.score, WIN_SCORE - 1);
+        assert_eq!(result.pv.len(), 1);
+        assert_eq!(game.get_state().to_tps(), "1,1,1,x/2,2,2,x/x4/x4 1 4");
+
+        // Black threatens d3 and white has no road of its own, so it must block
+        let state = State::from_tps("1,1,x2/2,2,2,x/x4/x,1,x2 1 4").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        let result = search(&mut *game.rules, SearchLimits::depth(2));
+        assert_eq!(result.best_move.unwrap().to_string(), "d3");
+        assert_eq!(result.depth, 2);
+        assert_eq!(result.pv.len(), 2);
+
+        let mut game = make_standard_game(GameConfig::new(6));
+        let start = Instant::now();
+        let result = search(
+            &mut *game.rules,
+            SearchLimits::time(Duration::from_millis(200)),
+        );
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(game.legal_move(result.best_move.unwrap()));
+    }
+
+    #[test]
+    fn test_mcts() {
+        use engine::mcts::{Mcts, MctsConfig};
+
+        let state = State::from_tps("1,1,1,x/2,2,2,x/x4/x4 1 4").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        let config = MctsConfig {
+            iterations: Some(200),
+            playout_limit: 30,
+            exploration: 0.5,
+            ..MctsConfig::default()
+        };
+        let result = Mcts::new(config).search(&mut *game.rules);
+        assert_eq!(result.iterations, 200);
+        assert_eq!(result.stats.len(), 28);
+        assert_eq!(result.best_move.unwrap().to_string(), "d4");
+        assert_eq!(result.stats[0].value, 1.0);
+        let visits: u32 = result.stats.iter().map(|s| s.visits).sum();
+        assert_eq!(visits, 200);
+        assert_eq!(game.get_state().to_tps(), "1,1,1,x/2,2,2,x/x4/x4 1 4");
+
+        let mut game = make_standard_game(GameConfig::new(4));
+        let config = MctsConfig {
+            iterations: Some(50),
+            playout_limit: 30,
+            seed: 7,
+            ..MctsConfig::default()
+        };
+        let first = Mcts::new(config).search(&mut *game.rules);
+        let second = Mcts::new(config).search(&mut *game.rules);
+        assert_eq!(first.best_move, second.best_move);
+        assert_eq!(first.stats.len(), 16);
+        assert!(game.get_state().history.is_empty());
+    }
+
+    #[test]
+    fn test_tei() {
+        use engine::tei::TeiEngine;
+
+        let mut engine = TeiEngine::new();
+        let response = engine.handle("tei");
+        assert!(response[0].starts_with("id name"));
+        assert_eq!(response.last().unwrap(), "teiok");
+        assert_eq!(engine.handle("isready"), vec!["readyok"]);
+        assert!(engine.handle("teinewgame 4").is_empty());
+        assert_eq!(engine.get_game().get_size(), 4);
+        assert!(engine.handle("unknown command").is_empty());
+
+        assert!(engine
+            .handle("position startpos moves a1 d4 b2 c3 b1")
+            .is_empty());
+        assert_eq!(engine.get_game().get_state().ply(), 5);
+        let response = engine.handle("go depth 2");
+        assert_eq!(response.len(), 2);
+        assert!(response[0].starts_with("info depth 2"));
+        let best = response[1].trim_start_matches("bestmove ");
+        assert!(engine.get_game().legal_move(ptn_move(best).unwrap()));
+
+        assert!(engine
+            .handle("position tps 1,1,1,x/2,2,2,x/x4/x4 1 4")
+            .is_empty());
+        assert_eq!(engine.handle("go movetime 200")[1], "bestmove d4");
+        assert_eq!(engine.handle("go wtime 4000 btime 4000")[1], "bestmove d4");
+
+        assert_eq!(
+            engine.handle("position startpos moves a1 a1"),
+            vec!["info string illegal move a1: Square is already occupied"]
+        );
+        assert_eq!(engine.get_game().get_state().ply(), 6);
+        // Crush markers are checked against the position
+        let crush = "position tps x5/x5/x,2S,x3/x,1C,x3/x5 1 3 moves ";
+        assert_eq!(
+            engine.handle(&format!("{}b2+", crush)),
+            vec!["info string invalid move b2+: Crush marker does not match the flattening of a wall"]
+        );
+        assert!(engine.handle(&format!("{}b2+*", crush)).is_empty());
+        assert_eq!(engine.get_game().get_state().ply(), 5);
+        assert_eq!(
+            engine.handle("teinewgame 9"),
+            vec!["info string unsupported size 9"]
+        );
+    }
+
+    #[test]
+    fn test_clock() {
+        use game::clock::{Clock, ManualTime, TimeControl};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let secs = Duration::from_secs;
+        let time = Arc::new(ManualTime::new());
+        let control = TimeControl::new(secs(60), secs(5)).with_delay(secs(2));
+        let mut game = make_standard_game(GameConfig::new(5));
+        game.set_clock(Clock::new(control, time.clone()));
+        let remaining = |game: &Game, color| game.clock.as_ref().unwrap().remaining(color);
+
+        time.advance(secs(1));
+        assert_eq!(remaining(&game, Color::White), secs(60));
+        time.advance(secs(10));
+        assert_eq!(remaining(&game, Color::White), secs(51));
+        game.do_ply(ptn_move("a1").unwrap()).unwrap();
+        assert_eq!(remaining(&game, Color::White), secs(56));
+        time.advance(secs(30));
+        assert_eq!(remaining(&game, Color::Black), secs(32));
+        assert_eq!(remaining(&game, Color::White), secs(56));
+        game.do_ply(ptn_move("e5").unwrap()).unwrap();
+        assert_eq!(remaining(&game, Color::Black), secs(37));
+        assert_eq!(game.check_time(), Victory::Neither);
+
+        time.advance(secs(58));
+        assert_eq!(game.check_time(), Victory::BlackOther);
+        assert!(game.is_over());
+        assert_eq!(game.result(), Victory::BlackOther);
+        assert!(game.do_ply(ptn_move("b2").unwrap()).is_err());
+        assert!(game.resign(Color::Black).is_err());
+        assert_eq!(game.result(), Victory::BlackOther);
+        assert_eq!(game.get_state().ply(), 2);
+
+        // The flag fall ends the game even if the clock was not checked before acting
+        let mut game = make_standard_game(GameConfig::new(5));
+        game.set_clock(Clock::new(control, time.clone()));
+        time.advance(secs(62));
+        assert!(game.offer_draw(Color::Black).is_err());
+        assert_eq!(game.result(), Victory::BlackOther);
+        assert_eq!(game.check_time(), Victory::Neither);
+
+        // A clock set on a game in progress runs for the player to move
+        let state = State::from_tps("x5/x5/x5/x5/1,x3,2 2 1").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        game.set_clock(Clock::new(
+            TimeControl::new(secs(10), secs(0)),
+            time.clone(),
+        ));
+        time.advance(secs(10));
+        assert_eq!(game.check_time(), Victory::WhiteOther);
+        assert_eq!(remaining(&game, Color::White), secs(10));
+    }
+
+    #[test]
+    fn test_game_actions() {
+        use game::database::write_ptn;
+
+        let play = |game: &mut Game, ptn: &str| game.do_ply(ptn_move(ptn).unwrap());
+        let mut game = make_standard_game(GameConfig::new(5));
+        play(&mut game, "a1").unwrap();
+        play(&mut game, "e5").unwrap();
+        assert!(game.abort().is_err());
+        assert_eq!(game.resign(Color::Black).unwrap(), Victory::WhiteOther);
+        assert_eq!(game.result(), Victory::WhiteOther);
+        assert!(play(&mut game, "b2").is_err());
+        assert!(game.resign(Color::White).is_err());
+        assert!(write_ptn(&game, &[]).ends_with("1. a1 e5 1-0\n"));
+
+        let mut game = make_standard_game(GameConfig::new(5));
+        play(&mut game, "a1").unwrap();
+        assert_eq!(game.abort().unwrap(), Victory::Draw);
+
+        let mut game = make_standard_game(GameConfig::new(5));
+        assert!(game.accept_draw(Color::Black).is_err());
+        assert_eq!(game.offer_draw(Color::White).unwrap(), Victory::Neither);
+        assert!(game.accept_draw(Color::White).is_err());
+        game.decline_draw(Color::Black).unwrap();
+        assert_eq!(game.draw_offer(), None);
+        game.offer_draw(Color::White).unwrap();
+        // White's own move leaves its offer standing
+        play(&mut game, "a1").unwrap();
+        assert_eq!(game.draw_offer(), Some(Color::White));
+        // Black moving instead of answering declines the offer
+        play(&mut game, "e5").unwrap();
+        assert_eq!(game.draw_offer(), None);
+        game.offer_draw(Color::White).unwrap();
+        assert_eq!(game.offer_draw(Color::Black).unwrap(), Victory::Draw);
+        assert!(write_ptn(&game, &[]).contains("[Result \"1/2-1/2\"]"));
+
+        let mut game = make_standard_game(GameConfig::new(5));
+        game.offer_draw(Color::Black).unwrap();
+        assert_eq!(game.accept_draw(Color::White).unwrap(), Victory::Draw);
+
+        let mut game = make_standard_game(GameConfig::new(5));
+        assert_eq!(game.abandon(Color::White).unwrap(), Victory::BlackOther);
+        assert!(game.offer_draw(Color::Black).is_err());
+        assert!(write_ptn(&game, &[]).ends_with("0-1\n"));
+    }
+
+    #[test]
+    fn test_game_over() {
+        let mut game = make_standard_game(GameConfig::new(3));
+        for ptn in ["a1", "c3", "c2", "a2"].iter() {
+            game.do_ply(ptn_move(ptn).unwrap()).unwrap();
+        }
+        assert!(!game.is_over());
+        assert_eq!(game.result(), Victory::Neither);
+        assert_eq!(
+            game.do_ply(ptn_move("c1").unwrap()).unwrap(),
+            Victory::WhiteRoad
+        );
+        assert!(game.is_over());
+        assert_eq!(game.result(), Victory::WhiteRoad);
+        let tps = game.get_state().to_tps();
+        let error = game.do_ply(ptn_move("b2").unwrap()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<GameOver>(),
+            Some(&GameOver {
+                result: Victory::WhiteRoad
+            })
+        );
+        assert_eq!(game.get_state().to_tps(), tps);
+        assert!(game.resign(Color::White).is_err());
+
+        // Taking back the winning move resumes the game
+        game.undo_ply().unwrap();
+        assert!(!game.is_over());
+        game.do_ply(ptn_move("b2").unwrap()).unwrap();
+        // Results not decided on the board survive taking back a move
+        game.resign(Color::Black).unwrap();
+        game.undo_ply().unwrap();
+        assert_eq!(game.result(), Victory::WhiteOther);
+        assert!(game.do_ply(ptn_move("b2").unwrap()).is_err());
+
+        let state = State::from_tps("1,1,1/2,2,x/x3 2 3").unwrap();
+        let mut game = Game::new(Box::new(StandardRules::new(state)));
+        assert_eq!(game.result(), Victory::WhiteRoad);
+        assert!(game.do_ply(ptn_move("c2").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_game_config() {
+        use game::database::{read_formatted_ptn, write_ptn};
+
+        for &(size, stones, caps) in [(3, 10, 0), (6, 30, 1), (7, 40, 2), (8, 50, 2)].iter() {
+            let state = State::new(size);
+            assert_eq!(state.player1.pieces, stones);
+            assert_eq!(state.player2.caps, caps);
+            assert_eq!(GameConfig::new(size).state().player2.pieces, stones);
+        }
+        assert_eq!(standard_reserves(9), None);
+        for &size in [0, 2, 9].iter() {
+            assert!(State::try_new(size).is_err());
+            assert!(GameConfig::try_new(size).is_err());
+        }
+        assert_eq!(State::try_new(7).unwrap().player1.caps, 2);
+        assert_eq!(GameConfig::try_new(4).unwrap(), GameConfig::new(4));
+
+        let game = make_standard_game(GameConfig::new(7));
+        assert_eq!(game.get_size(), 7);
+        assert!(write_ptn(&game, &[]).contains("[Flats \"40\"]\n[Caps \"2\"]"));
+        let mut game = make_standard_game(GameConfig::new(7));
+        assert!(game.do_ply(ptn_move("g7").unwrap()).is_ok());
+
+        let config = GameConfig::new(6).with_komi(2).with_reserves(25, 0);
+        let mut game = make_standard_game(config);
+        assert_eq!(game.rules.komi(), 2);
+        assert_eq!(game.get_state().player1.pieces, 25);
+        game.do_ply(ptn_move("a1").unwrap()).unwrap();
+        game.do_ply(ptn_move("f6").unwrap()).unwrap();
+        assert!(game.do_ply(ptn_move("Cb2").unwrap()).is_err());
+        let ptn = write_ptn(&game, &[]);
+        assert!(ptn.contains("[Komi \"2\"]\n[Flats \"25\"]\n[Caps \"0\"]"));
+        let read = read_formatted_ptn(&ptn).unwrap().new_game().unwrap();
+        assert_eq!(read.rules.komi(), 2);
+        assert_eq!(read.get_state().player1.caps, 0);
+        assert_eq!(read.get_state().player2.pieces, 25);
+    }
+
+    #[test]
+    fn test_server_lobby() {
+        use game::clock::ManualTime;
+        use server::{Lobby, Message};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let texts = |messages: Vec<Message>, to| -> Vec<String> {
+            messages
+                .into_iter()
+                .filter(|m| m.to == to)
+                .map(|m| m.text)
+                .collect()
+        };
+        let time = Arc::new(ManualTime::new());
+        let mut lobby = Lobby::with_time_source(time.clone());
+        let (alice, greeting) = lobby.connect();
+        assert_eq!(
+            texts(greeting, alice),
+            vec!["Welcome!", "Login or Register"]
+        );
+        let (bob, _) = lobby.connect();
+        assert_eq!(
+            texts(lobby.handle(alice, "Seek 3 600 10"), alice),
+            vec!["NOK"]
+        );
+        assert_eq!(
+            texts(lobby.handle(alice, "Login alice"), alice),
+            vec!["Welcome alice!"]
+        );
+        assert_eq!(
+            texts(lobby.handle(bob, "Login alice"), bob),
+            vec!["Authentication failure"]
+        );
+        let out = lobby.handle(alice, "Seek 3 600 10 B");
+        assert_eq!(texts(out, alice), vec!["Seek new 1 alice 3 600 10 B"]);
+        assert_eq!(
+            texts(lobby.handle(bob, "Login bob"), bob),
+            vec!["Welcome bob!", "Seek new 1 alice 3 600 10 B"]
+        );
+        let out = lobby.handle(bob, "Accept 1");
+        assert_eq!(
+            texts(out.clone(), bob),
+            vec![
+                "Seek remove 1 alice 3 600 10 B",
+                "Game Start 1 3 bob vs alice white 600"
+            ]
+        );
+        assert!(texts(out, alice).contains(&String::from("Game Start 1 3 bob vs alice black 600")));
+        assert_eq!(lobby.seeks().count(), 0);
+
+        // Out of turn, malformed and illegal moves are all refused
+        assert_eq!(
+            texts(lobby.handle(alice, "Game#1 P A1"), alice),
+            vec!["NOK"]
+        );
+        assert_eq!(texts(lobby.handle(bob, "Game#1 P A9"), bob), vec!["NOK"]);
+        assert_eq!(texts(lobby.handle(bob, "Game#1 P A1 C"), bob), vec!["NOK"]);
+        let out = lobby.handle(bob, "Game#1 P A1");
+        assert_eq!(texts(out.clone(), bob), vec!["OK", "Game#1 Time 610 600"]);
+        assert_eq!(
+            texts(out, alice),
+            vec!["Game#1 P A1", "Game#1 Time 610 600"]
+        );
+        lobby.handle(alice, "Game#1 P C3");
+
+        let (carol, _) = lobby.connect();
+        lobby.handle(carol, "Login Guest");
+        assert_eq!(lobby.name(carol), Some("Guest1"));
+        assert_eq!(
+            texts(lobby.handle(carol, "Observe 1"), carol),
+            vec![
+                "Observe Game#1 3 bob vs alice 600",
+                "Game#1 P A1",
+                "Game#1 P C3"
+            ]
+        );
+        lobby.handle(bob, "Game#1 P C2");
+        lobby.handle(alice, "Game#1 P A2");
+        time.advance(Duration::from_secs(25));
+        let out = lobby.handle(bob, "Game#1 P C1");
+        assert_eq!(
+            texts(out.clone(), alice),
+            vec!["Game#1 P C1", "Game#1 Time 605 620", "Game#1 Over R-0"]
+        );
+        assert_eq!(
+            texts(out.clone(), carol),
+            vec!["Game#1 P C1", "Game#1 Time 605 620", "Game#1 Over R-0"]
+        );
+        assert_eq!(
+            texts(out, bob),
+            vec!["OK", "Game#1 Time 605 620", "Game#1 Over R-0"]
+        );
+        assert!(lobby.game(1).is_none());
+
+        lobby.handle(alice, "Seek 4 60 0");
+        lobby.handle(carol, "Accept 2");
+        assert_eq!(lobby.game(2).unwrap().game.get_size(), 4);
+        time.advance(Duration::from_secs(59));
+        assert!(lobby.check_clocks().is_empty());
+        time.advance(Duration::from_secs(1));
+        assert_eq!(texts(lobby.check_clocks(), carol), vec!["Game#2 Over 0-1"]);
+        assert!(lobby.game(2).is_none());
+
+        // A move made after the flag fell ends the game instead
+        lobby.handle(alice, "Seek 4 60 0");
+        lobby.handle(carol, "Accept 3");
+        time.advance(Duration::from_secs(61));
+        assert_eq!(
+            texts(lobby.handle(alice, "Game#3 P A1"), alice),
+            vec!["Game#3 Over 0-1"]
+        );
+
+        lobby.handle(alice, "Seek 4 0 0");
+        lobby.handle(carol, "Accept 4");
+        assert!(lobby.game(4).unwrap().game.clock.is_none());
+        assert_eq!(texts(lobby.handle(alice, "Game#4 P A1"), alice), vec!["OK"]);
+        let out = lobby.handle(alice, "Game#4 OfferDraw");
+        assert_eq!(texts(out.clone(), alice), vec!["OK"]);
+        assert_eq!(texts(out, carol), vec!["Game#4 OfferDraw"]);
+        let out = lobby.disconnect(carol);
+        assert!(texts(out, alice).contains(&String::from("Game#4 Over 1-0")));
+        assert!(lobby.game(4).is_none());
+
+        lobby.handle(alice, "Seek 5 0 0");
+        lobby.handle(bob, "Accept 5");
+        lobby.handle(bob, "Game#5 OfferDraw");
+        let out = lobby.handle(alice, "Game#5 OfferDraw");
+        assert_eq!(texts(out, bob), vec!["Game#5 Over 1/2-1/2"]);
+        lobby.handle(alice, "Seek 5 0 0");
+        lobby.handle(bob, "Accept 6");
+        let out = lobby.handle(bob, "Game#6 Resign");
+        assert_eq!(texts(out, alice), vec!["Game#6 Over 1-0"]);
+        assert_eq!(lobby.handle(bob, "Game#6 Resign")[0].text, "NOK");
+    }
+
+    #[test]
+    fn test_server_tcp() {
+        use server::Server;
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpStream;
+
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        server.spawn();
+        let connect = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            (stream.try_clone().unwrap(), BufReader::new(stream).lines())
+        };
+        let (mut white, mut white_lines) = connect();
+        let (mut black, mut black_lines) = connect();
+        let read =
+            |lines: &mut std::io::Lines<BufReader<TcpStream>>| lines.next().unwrap().unwrap();
+        assert_eq!(read(&mut white_lines), "Welcome!");
+        assert_eq!(read(&mut white_lines), "Login or Register");
+        writeln!(white, "Login white").unwrap();
+        assert_eq!(read(&mut white_lines), "Welcome white!");
+        writeln!(white, "Seek 5 900 0 W").unwrap();
+        assert_eq!(read(&mut white_lines), "Seek new 1 white 5 900 0 W");
+        read(&mut black_lines);
+        read(&mut black_lines);
+        writeln!(black, "Login black").unwrap();
+        assert_eq!(read(&mut black_lines), "Welcome black!");
+        assert_eq!(read(&mut black_lines), "Seek new 1 white 5 900 0 W");
+        writeln!(black, "Accept 1").unwrap();
+        assert_eq!(read(&mut black_lines), "Seek remove 1 white 5 900 0 W");
+        assert_eq!(
+            read(&mut black_lines),
+            "Game Start 1 5 white vs black black 900"
+        );
+        assert_eq!(read(&mut white_lines), "Seek remove 1 white 5 900 0 W");
+        assert_eq!(
+            read(&mut white_lines),
+            "Game Start 1 5 white vs black white 900"
+        );
+        writeln!(white, "Game#1 P E5").unwrap();
+        assert_eq!(read(&mut white_lines), "OK");
+        assert!(read(&mut white_lines).starts_with("Game#1 Time"));
+        assert_eq!(read(&mut black_lines), "Game#1 P E5");
+        assert!(read(&mut black_lines).starts_with("Game#1 Time"));
+        writeln!(black, "quit").unwrap();
+        assert_eq!(read(&mut white_lines), "Game#1 Over 1-0");
+    }
+
+    #[test]
+    fn test_client_events() {
+        use client::*;
+
+        assert_eq!(
+            parse_event("Game Start 4 5 alice vs bob black 900 0 21 1").unwrap(),
+            Event::GameStart {
+                id: 4,
+                size: 5,
+                white: String::from("alice"),
+                black: String::from("bob"),
+                color: Color::Black,
+                time: 900,
+            }
+        );
+        assert_eq!(
+            parse_event("Game#4 Time 850 893").unwrap(),
+            Event::Time {
+                id: 4,
+                white: 850,
+                black: 893
+            }
+        );
+        assert_eq!(
+            parse_event("Game#4 Over 0-F").unwrap(),
+            Event::Over {
+                id: 4,
+                result: Victory::BlackFlat(0)
+            }
+        );
+        assert_eq!(
+            parse_event("Seek new 7 carol 6 600 20 W").unwrap(),
+            Event::SeekNew(SeekInfo {
+                id: 7,
+                name: String::from("carol"),
+                size: 6,
+                time: 600,
+                increment: 20,
+                color: Some(Color::White),
+            })
+        );
+        assert_eq!(
+            parse_event("Shout <carol> hi").unwrap(),
+            Event::Other(String::from("Shout <carol> hi"))
+        );
+        assert_eq!(
+            parse_event("Game#4 OfferDraw").unwrap(),
+            Event::DrawOffer { id: 4 }
+        );
+        assert_eq!(
+            Command::Resign(4).to_line(),
+            Some(String::from("Game#4 Resign"))
+        );
+        assert!(parse_event("Game Start 4 261 alice vs bob black 900").is_err());
+        assert!(parse_event("Seek new 1 alice 261 600 10 B").is_err());
+        assert!(parse_event("Game#4 M A1 B2 1").is_err());
+        assert!(parse_event("Game#4 Over 2-0").is_err());
+
+        let mut client = Client::new();
+        assert!(matches!(
+            client.handle("Game#1 P A1"),
+            Err(ClientError::UnknownGame(1))
+        ));
+        client.handle("Observe Game#1 3 alice vs bob 600").unwrap();
+        client.handle("Game#1 P A1").unwrap();
+        client.handle("Game#1 P C3").unwrap();
+        assert!(matches!(
+            client.handle("Game#1 M A1 A2 1"),
+            Err(ClientError::IllegalMove { id: 1, .. })
+        ));
+        assert_eq!(client.game(1).unwrap().get_state().ply(), 2);
+        let command = client.play(1, ptn_move("c3-").unwrap()).unwrap();
+        assert_eq!(command.to_line(), Some(String::from("Game#1 M C3 C2 1")));
+        // The move is only made once the server accepts it
+        assert_eq!(client.game(1).unwrap().get_state().ply(), 2);
+        assert_eq!(client.handle("NOK").unwrap(), Event::Nok);
+        assert_eq!(client.game(1).unwrap().get_state().ply(), 2);
+        client.play(1, ptn_move("c3-").unwrap()).unwrap();
+        assert_eq!(client.handle("OK").unwrap(), Event::Ok);
+        assert_eq!(
+            client.game(1).unwrap().get_state().to_tps(),
+            "x3/x2,1/2,x2 2 2"
+        );
+        assert!(matches!(
+            client.play(1, ptn_move("c1+").unwrap()),
+            Err(ClientError::IllegalMove { id: 1, .. })
+        ));
+        assert_eq!(
+            Command::Seek {
+                size: 5,
+                time: 900,
+                increment: 10,
+                color: Some(Color::Black)
+            }
+            .to_line(),
+            Some(String::from("Seek 5 900 10 B"))
+        );
+        assert_eq!(
+            Command::Login {
+                name: String::from("Guest"),
+                password: None
+            }
+            .to_line(),
+            Some(String::from("Login Guest"))
+        );
+        let off_board = Move::Place(PieceKind::Flat, Square::new(8, 0));
+        assert_eq!(Command::Move(1, off_board).to_line(), None);
+    }
+
+    #[test]
+    fn test_client_connection() {
+        use client::{Command, Connection, Event};
+        use game::clock::ManualTime;
+        use server::Server;
+        use std::sync::Arc;
+
+        // The clocks stand still, so the times reported do not depend on how fast the test runs
+        let server =
+            Server::bind_with_time_source("127.0.0.1:0", Arc::new(ManualTime::new())).unwrap();
+        let addr = server.local_addr().unwrap();
+        server.spawn();
+        let login = |name: &str| {
+            let mut connection = Connection::connect(addr).unwrap();
+            connection
+                .send(&Command::Login {
+                    name: name.to_string(),
+                    password: None,
+                })
+                .unwrap();
+            loop {
+                if let Event::Welcome(_) = connection.next_event().unwrap().unwrap() {
+                    return connection;
+                }
+            }
+        };
+        let mut white = login("white");
+        white
+            .send(&Command::Seek {
+                size: 3,
+                time: 60,
+                increment: 0,
+                color: Some(Color::White),
+            })
+            .unwrap();
+        let mut black = login("black");
+        let seek = match black.next_event().unwrap().unwrap() {
+            Event::SeekNew(seek) => seek,
+            event => panic!("Unexpected event {:?}", event),
+        };
+        black.send(&Command::Accept(seek.id)).unwrap();
+        let start = |connection: &mut Connection| loop {
+            if let Event::GameStart { id, color, .. } = connection.next_event().unwrap().unwrap() {
+                return (id, color);
+            }
+        };
+        let (id, color) = start(&mut black);
+        assert!(color == Color::Black);
+        assert_eq!(start(&mut white), (id, Color::White));
+
+        let moves = ["a1", "c3", "c2", "a2", "c1"];
+        for (ply, ptn) in moves.iter().enumerate() {
+            let (mover, other) = if ply % 2 == 0 {
+                (&mut white, &mut black)
+            } else {
+                (&mut black, &mut white)
+            };
+            mover.play(id, ptn_move(ptn).unwrap()).unwrap();
+            assert_eq!(mover.next_event().unwrap(), Some(Event::Ok));
+            let time = Some(Event::Time {
+                id,
+                white: 60,
+                black: 60,
+            });
+            assert_eq!(mover.next_event().unwrap(), time);
+            assert_eq!(
+                other.next_event().unwrap(),
+                Some(Event::Move {
+                    id,
+                    m: ptn_move(ptn).unwrap()
+                })
+            );
+            assert_eq!(other.next_event().unwrap(), time);
+        }
+        for connection in [&mut white, &mut black].iter_mut() {
+            assert_eq!(
+                connection.next_event().unwrap(),
+                Some(Event::Over {
+                    id,
+                    result: Victory::WhiteRoad
+                })
             );
-        } else {
-            return (Vec::new(), String::from("0-0"), 5);
+            let game = connection.client().game(id).unwrap();
+            assert_eq!(game.rules.check_win(), Victory::WhiteRoad);
+            assert_eq!(game.get_state().to_tps(), "x2,1/2,x,1/2,x,1 2 3");
         }
     }
 }
@@ -0,0 +1,571 @@
+use crate::game::clock::{Clock, RealTime, TimeControl, TimeSource};
+use crate::game::database::{decode_playtak_move, encode_playtak_move};
+use crate::game::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the server checks the clocks of games for flag fall
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type ClientId = usize;
+
+///A line of text to be sent to a client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub to: ClientId,
+    pub text: String,
+}
+
+impl Message {
+    fn new(to: ClientId, text: String) -> Message {
+        Message { to, text }
+    }
+}
+
+///An open offer to play a game, which any other player may accept
+#[derive(Clone, Debug)]
+pub struct Seek {
+    pub id: u32,
+    pub owner: ClientId,
+    pub size: u8,
+    /// Initial time in seconds
+    pub time: u32,
+    /// Increment in seconds
+    pub increment: u32,
+    /// The color the owner wants to play, or None to leave it open
+    pub color: Option<Color>,
+}
+
+///A game being played on the server. Games with a time control have a clock on their Game.
+pub struct HostedGame {
+    pub id: u32,
+    pub game: Game,
+    pub white: ClientId,
+    pub black: ClientId,
+    pub time: u32,
+    pub observers: Vec<ClientId>,
+}
+
+impl HostedGame {
+    fn player_color(&self, client: ClientId) -> Option<Color> {
+        if client == self.white {
+            Some(Color::White)
+        } else if client == self.black {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    ///The players and observers of the game
+    fn audience(&self) -> Vec<ClientId> {
+        let mut audience = vec![self.white, self.black];
+        audience.extend(self.observers.iter().cloned());
+        audience
+    }
+
+    ///The Time message giving the remaining seconds of each player, if the game has a clock
+    fn time_line(&self) -> Option<String> {
+        let clock = self.game.clock.as_ref()?;
+        let seconds = |color| (clock.remaining(color).as_millis() + 500) / 1000;
+        Some(format!(
+            "Game#{} Time {} {}",
+            self.id,
+            seconds(Color::White),
+            seconds(Color::Black)
+        ))
+    }
+}
+
+///The state shared by every connection to a server: who is logged in, the open seeks and the games
+/// in progress. Each input line is handled in turn, producing the messages to send in response,
+/// which keeps the protocol independent of the network.
+pub struct Lobby {
+    time: Arc<dyn TimeSource>,
+    names: HashMap<ClientId, Option<String>>,
+    seeks: BTreeMap<u32, Seek>,
+    games: BTreeMap<u32, HostedGame>,
+    next_client: ClientId,
+    next_seek: u32,
+    next_game: u32,
+    next_guest: u32,
+}
+
+impl Default for Lobby {
+    fn default() -> Lobby {
+        Lobby::new()
+    }
+}
+
+impl Lobby {
+    pub fn new() -> Lobby {
+        Lobby::with_time_source(Arc::new(RealTime::new()))
+    }
+
+    ///Creates a lobby whose game clocks read the given time source
+    pub fn with_time_source(time: Arc<dyn TimeSource>) -> Lobby {
+        Lobby {
+            time,
+            names: HashMap::new(),
+            seeks: BTreeMap::new(),
+            games: BTreeMap::new(),
+            next_client: 0,
+            next_seek: 0,
+            next_game: 0,
+            next_guest: 0,
+        }
+    }
+
+    ///Registers a new connection, returning its id and the greeting to send
+    pub fn connect(&mut self) -> (ClientId, Vec<Message>) {
+        self.next_client += 1;
+        let client = self.next_client;
+        self.names.insert(client, None);
+        let greeting = vec![
+            Message::new(client, String::from("Welcome!")),
+            Message::new(client, String::from("Login or Register")),
+        ];
+        (client, greeting)
+    }
+
+    ///Removes a connection, withdrawing its seeks and forfeiting its games
+    pub fn disconnect(&mut self, client: ClientId) -> Vec<Message> {
+        let mut out = self.remove_seeks(client);
+        let games: Vec<u32> = self
+            .games
+            .values()
+            .filter(|g| g.player_color(client).is_some())
+            .map(|g| g.id)
+            .collect();
+        for id in games {
+            let game = self.games.get_mut(&id).unwrap();
+            let color = game.player_color(client).unwrap();
+            if let Ok(result) = game.game.abandon(color) {
+                out.extend(self.end_game(id, result));
+            }
+        }
+        for game in self.games.values_mut() {
+            game.observers.retain(|&c| c != client);
+        }
+        self.names.remove(&client);
+        out.retain(|m| m.to != client);
+        out
+    }
+
+    pub fn name(&self, client: ClientId) -> Option<&str> {
+        self.names.get(&client)?.as_deref()
+    }
+
+    pub fn seeks(&self) -> impl Iterator<Item = &Seek> {
+        self.seeks.values()
+    }
+
+    pub fn game(&self, id: u32) -> Option<&HostedGame> {
+        self.games.get(&id)
+    }
+
+    ///Handles a line sent by a client. Malformed or unexpected commands are answered with NOK.
+    pub fn handle(&mut self, client: ClientId, line: &str) -> Vec<Message> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let out = match (tokens.first(), self.name(client)) {
+            (Some(&"Login"), None) => self.login(client, &tokens[1..]),
+            (Some(&"Seek"), Some(_)) => self.seek(client, &tokens[1..]),
+            (Some(&"Accept"), Some(_)) => self.accept(client, &tokens[1..]),
+            (Some(&"Observe"), Some(_)) => self.observe(client, &tokens[1..]),
+            (Some(&"Unobserve"), Some(_)) => self.unobserve(client, &tokens[1..]),
+            (Some(command), Some(_)) if command.starts_with("Game#") => {
+                self.game_command(client, &tokens)
+            }
+            _ => None,
+        };
+        out.unwrap_or_else(|| vec![Message::new(client, String::from("NOK"))])
+    }
+
+    fn login(&mut self, client: ClientId, args: &[&str]) -> Option<Vec<Message>> {
+        let name = match args.first() {
+            Some(&"Guest") => {
+                self.next_guest += 1;
+                format!("Guest{}", self.next_guest)
+            }
+            Some(name) if name.chars().all(|c| c.is_alphanumeric() || c == '_') => name.to_string(),
+            _ => return None,
+        };
+        if self.names.values().any(|n| n.as_ref() == Some(&name)) {
+            return Some(vec![Message::new(
+                client,
+                String::from("Authentication failure"),
+            )]);
+        }
+        let mut out = vec![Message::new(client, format!("Welcome {}!", name))];
+        self.names.insert(client, Some(name));
+        for seek in self.seeks.values() {
+            out.push(Message::new(client, self.seek_line("new", seek)));
+        }
+        Some(out)
+    }
+
+    ///Handles `Seek <size> <time> <increment> [W|B]`, replacing any earlier seek by the client
+    fn seek(&mut self, client: ClientId, args: &[&str]) -> Option<Vec<Message>> {
+        let size: u8 = args.first()?.parse().ok()?;
+        let time = args.get(1)?.parse().ok()?;
+        let increment = args.get(2)?.parse().ok()?;
+        let color = match args.get(3) {
+            Some(&"W") => Some(Color::White),
+            Some(&"B") => Some(Color::Black),
+            Some(&"A") | None => None,
+            Some(_) => return None,
+        };
+        GameConfig::try_new(size).ok()?;
+        let mut out = self.remove_seeks(client);
+        self.next_seek += 1;
+        let seek = Seek {
+            id: self.next_seek,
+            owner: client,
+            size,
+            time,
+            increment,
+            color,
+        };
+        out.extend(self.broadcast(&self.seek_line("new", &seek)));
+        self.seeks.insert(seek.id, seek);
+        Some(out)
+    }
+
+    fn seek_line(&self, action: &str, seek: &Seek) -> String {
+        let color = match seek.color {
+            Some(Color::White) => "W",
+            Some(Color::Black) => "B",
+            None => "A",
+        };
+        format!(
+            "Seek {} {} {} {} {} {} {}",
+            action,
+            seek.id,
+            self.name(seek.owner).unwrap_or_default(),
+            seek.size,
+            seek.time,
+            seek.increment,
+            color
+        )
+    }
+
+    fn remove_seeks(&mut self, client: ClientId) -> Vec<Message> {
+        let ids: Vec<u32> = self
+            .seeks
+            .values()
+            .filter(|s| s.owner == client)
+            .map(|s| s.id)
+            .collect();
+        let mut out = Vec::new();
+        for id in ids {
+            let seek = self.seeks.remove(&id).unwrap();
+            out.extend(self.broadcast(&self.seek_line("remove", &seek)));
+        }
+        out
+    }
+
+    ///Handles `Accept <seek>`, starting a game between the seeker and the client
+    fn accept(&mut self, client: ClientId, args: &[&str]) -> Option<Vec<Message>> {
+        let id: u32 = args.first()?.parse().ok()?;
+        let seek = self.seeks.get(&id)?.clone();
+        if seek.owner == client {
+            return None;
+        }
+        let mut out = self.remove_seeks(seek.owner);
+        out.extend(self.remove_seeks(client));
+        let (white, black) = match seek.color {
+            Some(Color::Black) => (client, seek.owner),
+            _ => (seek.owner, client),
+        };
+        self.next_game += 1;
+        let mut game = HostedGame {
+            id: self.next_game,
+            game: make_standard_game(GameConfig::new(seek.size)),
+            white,
+            black,
+            time: seek.time,
+            observers: Vec::new(),
+        };
+        if seek.time > 0 {
+            let control = TimeControl::new(
+                Duration::from_secs(u64::from(seek.time)),
+                Duration::from_secs(u64::from(seek.increment)),
+            );
+            game.game
+                .set_clock(Clock::new(control, Arc::clone(&self.time)));
+        }
+        for (player, color) in [(white, "white"), (black, "black")].iter() {
+            out.push(Message::new(
+                *player,
+                format!(
+                    "Game Start {} {} {} vs {} {} {}",
+                    game.id,
+                    seek.size,
+                    self.name(white).unwrap_or_default(),
+                    self.name(black).unwrap_or_default(),
+                    color,
+                    seek.time
+                ),
+            ));
+        }
+        self.games.insert(game.id, game);
+        Some(out)
+    }
+
+    ///Handles `Observe <game>`, sending the moves made so far and every move from then on
+    fn observe(&mut self, client: ClientId, args: &[&str]) -> Option<Vec<Message>> {
+        let id: u32 = args.first()?.parse().ok()?;
+        let game = self.games.get(&id)?;
+        let mut out = vec![Message::new(
+            client,
+            format!(
+                "Observe Game#{} {} {} vs {} {}",
+                id,
+                game.game.get_size(),
+                self.name(game.white).unwrap_or_default(),
+                self.name(game.black).unwrap_or_default(),
+                game.time
+            ),
+        )];
+        for m in game.game.get_state().history.iter() {
+            out.push(Message::new(
+                client,
+                format!("Game#{} {}", id, encode_playtak_move(m)?),
+            ));
+        }
+        let game = self.games.get_mut(&id)?;
+        if game.player_color(client).is_none() && !game.observers.contains(&client) {
+            game.observers.push(client);
+        }
+        Some(out)
+    }
+
+    fn unobserve(&mut self, client: ClientId, args: &[&str]) -> Option<Vec<Message>> {
+        let id: u32 = args.first()?.parse().ok()?;
+        self.games.get_mut(&id)?.observers.retain(|&c| c != client);
+        Some(Vec::new())
+    }
+
+    ///Handles `Game#<id> Resign`, `Game#<id> OfferDraw`, which accepts a draw already offered by
+    /// the opponent, and `Game#<id> P ...` and `Game#<id> M ...` from a player whose turn it is
+    fn game_command(&mut self, client: ClientId, tokens: &[&str]) -> Option<Vec<Message>> {
+        let id: u32 = tokens[0].trim_start_matches("Game#").parse().ok()?;
+        let game = self.games.get_mut(&id)?;
+        let color = game.player_color(client)?;
+        match tokens.get(1) {
+            Some(&"Resign") => {
+                let result = game.game.resign(color).ok()?;
+                return Some(self.end_game(id, result));
+            }
+            Some(&"OfferDraw") => {
+                let result = game.game.offer_draw(color).ok()?;
+                if result != Victory::Neither {
+                    return Some(self.end_game(id, result));
+                }
+                let opponent = match color {
+                    Color::White => game.black,
+                    Color::Black => game.white,
+                };
+                return Some(vec![
+                    Message::new(client, String::from("OK")),
+                    Message::new(opponent, format!("Game#{} OfferDraw", id)),
+                ]);
+            }
+            _ => {}
+        }
+        if color != game.game.side_to_move() {
+            return None;
+        }
+        // Decoding only accepts moves in the form they are encoded, so the text is passed on as is
+        let notation = tokens[1..].join(" ");
+        let m = decode_playtak_move(&notation)?;
+        let text = format!("Game#{} {}", id, notation);
+        let ply = game.game.get_state().ply();
+        let result = match game.game.do_ply(m) {
+            Ok(result) => result,
+            // Only a flag fall ends a game without it leaving the server
+            Err(_) if game.game.is_over() => game.game.result(),
+            Err(_) => return None,
+        };
+        // The flag fell before or while the move was being made, so it does not count
+        if game.game.get_state().ply() == ply {
+            return Some(self.end_game(id, result));
+        }
+        let mut out = vec![Message::new(client, String::from("OK"))];
+        for to in game.audience() {
+            if to != client {
+                out.push(Message::new(to, text.clone()));
+            }
+        }
+        if let Some(time) = game.time_line() {
+            for to in game.audience() {
+                out.push(Message::new(to, time.clone()));
+            }
+        }
+        if result != Victory::Neither {
+            out.extend(self.end_game(id, result));
+        }
+        Some(out)
+    }
+
+    ///Ends every game in which the player to move has run out of time
+    pub fn check_clocks(&mut self) -> Vec<Message> {
+        let flagged: Vec<(u32, Victory)> = self
+            .games
+            .values_mut()
+            .map(|g| (g.id, g.game.check_time()))
+            .filter(|(_, result)| *result != Victory::Neither)
+            .collect();
+        let mut out = Vec::new();
+        for (id, result) in flagged {
+            out.extend(self.end_game(id, result));
+        }
+        out
+    }
+
+    fn end_game(&mut self, id: u32, result: Victory) -> Vec<Message> {
+        let game = match self.games.remove(&id) {
+            Some(game) => game,
+            None => return Vec::new(),
+        };
+        let text = format!("Game#{} Over {}", id, result);
+        game.audience()
+            .into_iter()
+            .map(|to| Message::new(to, text.clone()))
+            .collect()
+    }
+
+    fn broadcast(&self, text: &str) -> Vec<Message> {
+        self.names
+            .iter()
+            .filter(|(_, name)| name.is_some())
+            .map(|(&to, _)| Message::new(to, text.to_string()))
+            .collect()
+    }
+}
+
+///The state shared by the threads of a server. Messages are queued for the writer thread of each
+/// connection rather than written while the lock is held, so a slow client holds up nobody else.
+struct Shared {
+    lobby: Lobby,
+    outboxes: HashMap<ClientId, Sender<String>>,
+}
+
+impl Shared {
+    fn deliver(&self, messages: Vec<Message>) {
+        for message in messages {
+            if let Some(outbox) = self.outboxes.get(&message.to) {
+                // A closed outbox means the client is gone, which its reader thread will notice
+                let _ = outbox.send(message.text);
+            }
+        }
+    }
+}
+
+///Locks the shared state. A panic while handling one connection leaves the lock poisoned, which
+/// should not bring down every other connection, so the poison is ignored.
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+///A TCP server speaking the playtak protocol, one line per command, with a thread per connection
+pub struct Server {
+    listener: TcpListener,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+        Server::bind_with_time_source(addr, Arc::new(RealTime::new()))
+    }
+
+    ///Creates a server whose game clocks read the given time source
+    pub fn bind_with_time_source<A: ToSocketAddrs>(
+        addr: A,
+        time: Arc<dyn TimeSource>,
+    ) -> io::Result<Server> {
+        let shared = Shared {
+            lobby: Lobby::with_time_source(time),
+            outboxes: HashMap::new(),
+        };
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            shared: Arc::new(Mutex::new(shared)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    ///Accepts connections until the listener fails
+    pub fn run(self) -> io::Result<()> {
+        let shared = Arc::clone(&self.shared);
+        thread::spawn(move || loop {
+            thread::sleep(CLOCK_CHECK_INTERVAL);
+            let mut shared = lock(&shared);
+            let messages = shared.lobby.check_clocks();
+            shared.deliver(messages);
+        });
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let shared = Arc::clone(&self.shared);
+            thread::spawn(move || {
+                let _ = serve_client(shared, stream);
+            });
+        }
+        Ok(())
+    }
+
+    ///Runs the server on a background thread
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+fn serve_client(shared: Arc<Mutex<Shared>>, stream: TcpStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let (outbox, queued) = mpsc::channel();
+    thread::spawn(move || write_lines(stream, queued));
+    let client = {
+        let mut shared = lock(&shared);
+        let (client, greeting) = shared.lobby.connect();
+        shared.outboxes.insert(client, outbox);
+        shared.deliver(greeting);
+        client
+    };
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim() == "quit" {
+            break;
+        }
+        let mut shared = lock(&shared);
+        let messages = shared.lobby.handle(client, &line);
+        shared.deliver(messages);
+    }
+    let mut shared = lock(&shared);
+    let messages = shared.lobby.disconnect(client);
+    shared.outboxes.remove(&client);
+    shared.deliver(messages);
+    Ok(())
+}
+
+///Writes the lines queued for a connection until its outbox is dropped or a write fails, then
+/// shuts the connection down so that its reader thread stops as well
+fn write_lines(mut stream: TcpStream, queued: Receiver<String>) {
+    for line in queued {
+        if writeln!(stream, "{}", line).is_err() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
@@ -0,0 +1,82 @@
+//! Positions pinning how games end under the official rules: roads take precedence over flat
+//! wins, a move completing roads for both players wins for the mover, and flats are counted once
+//! the board is full or a player has placed every stone and capstone.
+
+use rust_tak::game::*;
+
+fn from_tps(tps: &str) -> Game {
+    Game::new(Box::new(StandardRules::new(State::from_tps(tps).unwrap())))
+}
+
+fn play(game: &mut Game, ptn: &str) -> Victory {
+    game.do_ply(ptn_move(ptn).unwrap()).unwrap()
+}
+
+#[test]
+fn road_beats_flats() {
+    // Filling the board with a road wins by road although black has more flats
+    let mut game = from_tps("2,1S,2/1,1,x/2,1S,2 1 5");
+    assert_eq!(play(&mut game, "c2"), Victory::WhiteRoad);
+    // Completing a road with the last stone wins by road as well
+    let config = GameConfig::new(4).with_reserves(4, 0);
+    let mut game = Game::new(Box::new(StandardRules::new(config.state())));
+    for ptn in &["d4", "a1", "a2", "d3", "a3", "d2"] {
+        assert_eq!(play(&mut game, ptn), Victory::Neither);
+    }
+    assert_eq!(play(&mut game, "a4"), Victory::WhiteRoad);
+}
+
+#[test]
+fn double_road_wins_for_mover() {
+    // Moving the top of c1 completes white's road on row 2 and uncovers black's on row 1
+    let mut game = from_tps("x3/1,1,x/2,2,21 1 4");
+    assert_eq!(play(&mut game, "c1+"), Victory::WhiteRoad);
+    let mut game = from_tps("x3/2,2,x/1,1,12 2 4");
+    assert_eq!(play(&mut game, "c1+"), Victory::BlackRoad);
+}
+
+#[test]
+fn board_fill() {
+    let mut game = from_tps("1,2,1/2,2,1/1,1,x 2 5");
+    assert_eq!(play(&mut game, "c1"), Victory::WhiteFlat(5));
+    let mut game = from_tps("1,2,1/2,2,1/1,1,x 2 5");
+    assert_eq!(play(&mut game, "Sc1"), Victory::WhiteFlat(5));
+    let mut game = from_tps("2,1,2/1,1S,2/2,1,x 1 5");
+    assert_eq!(play(&mut game, "c1"), Victory::Draw);
+}
+
+#[test]
+fn reserve_exhaustion() {
+    let config = GameConfig::new(4).with_reserves(2, 1);
+    let mut game = Game::new(Box::new(StandardRules::new(config.state())));
+    for ptn in &["a1", "d4", "b2", "c3"] {
+        assert_eq!(play(&mut game, ptn), Victory::Neither);
+    }
+    // Both players are out of stones but still hold their capstones
+    assert!(!game.get_state().has_stones(Color::White));
+    assert_eq!(
+        game.rules
+            .legal_place_move(Piece::new(Color::White, PieceKind::Wall), Square::new(1, 2)),
+        Err(MoveError::NoStonesLeft)
+    );
+    let places: Vec<_> = game
+        .legal_moves()
+        .into_iter()
+        .filter(|m| matches!(m, Move::Place(..)))
+        .collect();
+    assert!(places
+        .iter()
+        .all(|m| matches!(m, Move::Place(PieceKind::Cap, _))));
+    assert_eq!(places.len(), 12);
+    // Placing the last capstone ends the game, and capstones are not counted as flats
+    assert_eq!(play(&mut game, "Cb3"), Victory::Draw);
+    assert!(game.is_over());
+
+    let config = GameConfig::new(4).with_reserves(2, 1);
+    let mut game = Game::new(Box::new(StandardRules::new(config.state())));
+    for ptn in &["a1", "d4", "b2", "Cc3", "d4<", "c3>"] {
+        assert_eq!(play(&mut game, ptn), Victory::Neither);
+    }
+    // White's last capstone ends the game although black still has a stone
+    assert_eq!(play(&mut game, "Ca4"), Victory::WhiteFlat(2));
+}
@@ -0,0 +1,107 @@
+//! Perft node counts pinning the move generator, legal_stack_move, capstone crushes and road
+//! detection. Every count was cross-checked against a separate move generator written
+//! independently from the rules rather than taken from this crate. The deeper counts are slow in
+//! debug builds and are ignored by default; run them with `cargo test --release -- --ignored`.
+
+use rust_tak::game::perft::*;
+use rust_tak::game::*;
+
+fn assert_perft(mut game: Game, counts: &[u64]) {
+    for (depth, count) in counts.iter().enumerate() {
+        assert_eq!(
+            perft(&mut *game.rules, depth as u32),
+            *count,
+            "depth {}",
+            depth
+        );
+    }
+}
+
+fn from_tps(tps: &str) -> Game {
+    Game::new(Box::new(StandardRules::new(State::from_tps(tps).unwrap())))
+}
+
+/// Exercises capstone crushes, walls blocking throws and the edge of the board. From depth 4 the
+/// capstone can move on from a wall it crushed, which must be left flat.
+const CRUSH_TPS: &str = "x4,2/x,2S,x3/x,1C,x,2,x/x2,1,x2/1,x3,2C 1 7";
+/// Tall stacks on a 6x6 board exercising the carry limit
+const STACKS_TPS: &str = "x2,2,x3/x,212,x,1,x2/x,2,1121C,x,2S,x/x,1,x,22221,x2/x3,2,x2/1,x5 2 12";
+/// Either player can complete a road, so many leaves are terminal
+const ROAD_TPS: &str = "1,1,1,x/2,2,2,x/x4/x4 1 4";
+const SMALL_TPS: &str = "2,x,1/x,12,x/21,x,2S 1 6";
+
+#[test]
+fn perft_start() {
+    assert_perft(
+        make_standard_game(GameConfig::new(3)),
+        &[1, 9, 72, 1200, 17792],
+    );
+    assert_perft(make_standard_game(GameConfig::new(4)), &[1, 16, 240, 7440]);
+    assert_perft(make_standard_game(GameConfig::new(5)), &[1, 25, 600, 43320]);
+    assert_perft(
+        make_standard_game(GameConfig::new(6)),
+        &[1, 36, 1260, 132720],
+    );
+    assert_perft(
+        make_standard_game(GameConfig::new(7)),
+        &[1, 49, 2352, 339696],
+    );
+    assert_perft(
+        make_standard_game(GameConfig::new(8)),
+        &[1, 64, 4032, 764064],
+    );
+}
+
+#[test]
+fn perft_positions() {
+    assert_perft(from_tps(CRUSH_TPS), &[1, 46, 2082, 93398]);
+    assert_perft(from_tps(STACKS_TPS), &[1, 112, 19971]);
+    assert_perft(from_tps(ROAD_TPS), &[1, 28, 799, 22459]);
+    assert_perft(from_tps(SMALL_TPS), &[1, 15, 273, 3673]);
+}
+
+#[test]
+fn perft_divide_sums() {
+    let mut game = from_tps(CRUSH_TPS);
+    let divide = perft_divide(&mut *game.rules, 2);
+    assert_eq!(divide.len(), 46);
+    assert_eq!(divide.iter().map(|(_, n)| n).sum::<u64>(), 2082);
+    let crush = divide
+        .iter()
+        .find(|(m, _)| m.to_string() == "b3+")
+        .expect("Capstone can crush the wall");
+    assert_eq!(crush.1, perft_after(CRUSH_TPS, "b3+", 1));
+    // The position is left unchanged
+    assert_eq!(game.get_state().to_tps(), CRUSH_TPS);
+}
+
+fn perft_after(tps: &str, ptn: &str, depth: u32) -> u64 {
+    let mut game = from_tps(tps);
+    game.do_ply(ptn_move(ptn).unwrap()).unwrap();
+    perft(&mut *game.rules, depth)
+}
+
+#[test]
+#[ignore]
+fn perft_deep() {
+    assert_perft(
+        make_standard_game(GameConfig::new(3)),
+        &[1, 9, 72, 1200, 17792, 271812, 3715592],
+    );
+    assert_perft(
+        make_standard_game(GameConfig::new(4)),
+        &[1, 16, 240, 7440, 216464],
+    );
+    assert_perft(
+        make_standard_game(GameConfig::new(5)),
+        &[1, 25, 600, 43320, 2999784],
+    );
+    assert_perft(
+        make_standard_game(GameConfig::new(6)),
+        &[1, 36, 1260, 132720, 13586048],
+    );
+    assert_perft(from_tps(CRUSH_TPS), &[1, 46, 2082, 93398, 4120422]);
+    assert_perft(from_tps(STACKS_TPS), &[1, 112, 19971, 2239324]);
+    assert_perft(from_tps(ROAD_TPS), &[1, 28, 799, 22459, 653806, 18994789]);
+    assert_perft(from_tps(SMALL_TPS), &[1, 15, 273, 3673, 55307, 737042]);
+}